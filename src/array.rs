@@ -1,39 +1,177 @@
+use std::fmt;
 use std::ops::{Add, Div, Mul, Sub};
+use std::rc::Rc;
 
+use crate::errors::ErrorType;
+use crate::parser::{self, Ast};
+
+/// An n-dimensional array. `shape` holds the length of each axis and `d` the
+/// elements in row-major order, so a scalar has an empty shape and one element.
+/// Outside the crate arrays are only built through `try_new` and the other
+/// constructors, so the shape always matches the number of elements.
 #[derive(Debug, Clone, PartialEq)]
 pub struct A {
-    pub rank: usize,
-    pub d: Vec<Prim>,
+    pub(crate) shape: Vec<usize>,
+    pub(crate) d: Vec<Prim>,
 }
 
 impl A {
-    pub(crate) fn new(rank: usize, d: Vec<Prim>) -> Self {
-        Self { rank, d }
+    pub(crate) fn new(shape: Vec<usize>, d: Vec<Prim>) -> Self {
+        debug_assert_eq!(shape.iter().product::<usize>(), d.len());
+        Self { shape, d }
+    }
+    /// An array of `shape` holding `d`, if it has as many elements as the
+    /// shape calls for.
+    pub fn try_new(shape: Vec<usize>, d: Vec<Prim>) -> Result<Self, ErrorType> {
+        if shape.iter().product::<usize>() != d.len() {
+            return Err(ErrorType::Msg("The shape doesn't match the number of elements."));
+        }
+        Ok(Self { shape, d })
     }
-    pub(crate) fn from_num(x: f64) -> Self {
+    pub fn scalar(x: Prim) -> Self {
         Self {
-            rank: 1,
-            d: vec![Prim::Num(x)],
+            shape: vec![],
+            d: vec![x],
         }
     }
-    pub(crate) fn from_nums(x: &[f64]) -> Self {
+    pub fn from_num(x: f64) -> Self {
+        Self::scalar(Prim::Num(x))
+    }
+    pub fn from_nums(x: &[f64]) -> Self {
         Self {
-            rank: 1,
+            shape: vec![x.len()],
             d: x.iter().map(|x| Prim::Num(*x)).collect(),
         }
     }
     pub(crate) fn from_str(x: &str) -> Self {
+        let d: Vec<_> = x.chars().map(Prim::Char).collect();
         Self {
-            rank: 1,
-            d: x.chars().map(|x| Prim::Char(x)).collect(),
+            shape: vec![d.len()],
+            d,
+        }
+    }
+
+    /// The length of each axis.
+    pub fn shape(&self) -> &[usize] {
+        &self.shape
+    }
+    /// The elements, in row-major order.
+    pub fn d(&self) -> &[Prim] {
+        &self.d
+    }
+
+    pub fn rank(&self) -> usize {
+        self.shape.len()
+    }
+
+    /// Number of items along the leading axis. A scalar counts as one item.
+    pub(crate) fn len(&self) -> usize {
+        self.shape.first().copied().unwrap_or(1)
+    }
+
+    /// Split the array into its major cells, the subarrays along the leading axis.
+    pub(crate) fn cells(&self) -> Vec<A> {
+        if self.shape.is_empty() {
+            return vec![self.clone()];
+        }
+        let cell_shape = self.shape[1..].to_vec();
+        let size: usize = cell_shape.iter().product();
+        (0..self.shape[0])
+            .map(|i| A::new(cell_shape.clone(), self.d[i * size..(i + 1) * size].to_vec()))
+            .collect()
+    }
+
+    /// Split the array into its cells of rank `k`, returning the frame of
+    /// leading axes the cells are laid out in along with the cells.
+    pub(crate) fn rank_cells(&self, k: usize) -> (Vec<usize>, Vec<A>) {
+        let split = self.rank() - k.min(self.rank());
+        let (frame, cell_shape) = self.shape.split_at(split);
+        let size: usize = cell_shape.iter().product();
+        let cells = (0..frame.iter().product())
+            .map(|i| A::new(cell_shape.to_vec(), self.d[i * size..(i + 1) * size].to_vec()))
+            .collect();
+        (frame.to_vec(), cells)
+    }
+
+    /// Join cells of equal shape into one array with `frame` as its leading
+    /// axes. Returns `None` if the cells differ in shape.
+    pub(crate) fn merge(frame: Vec<usize>, cells: Vec<A>) -> Option<A> {
+        let cell_shape = cells.first().map(|c| c.shape.clone()).unwrap_or_default();
+        if cells.iter().any(|c| c.shape != cell_shape) {
+            return None;
+        }
+
+        let mut shape = frame;
+        shape.extend(cell_shape);
+        Some(A::new(shape, cells.into_iter().flat_map(|c| c.d).collect()))
+    }
+
+    /// Join cells into one array with `frame` as its leading axes like
+    /// `merge`, but box the cells instead if they differ in shape.
+    pub(crate) fn from_cells(frame: Vec<usize>, cells: Vec<A>) -> A {
+        if cells.windows(2).all(|w| w[0].shape == w[1].shape) {
+            return A::merge(frame, cells).expect("cells have the same shape");
+        }
+
+        let d = cells
+            .into_iter()
+            .map(|c| match c.rank() {
+                0 => c.d.into_iter().next().expect("scalars hold one element"),
+                _ => Prim::Boxed(Box::new(c)),
+            })
+            .collect();
+        A::new(frame, d)
+    }
+
+    fn fmt_nested(&self, f: &mut fmt::Formatter, indent: usize, width: usize) -> fmt::Result {
+        match self.rank() {
+            0 => write!(f, "{:>1$}", self.d[0].to_string(), width),
+            1 if !self.d.is_empty() && self.d.iter().all(|x| x.as_char().is_some()) => {
+                // Shown as written, since string literals have no escapes.
+                let s: String = self.d.iter().filter_map(|x| x.as_char()).collect();
+                write!(f, "\"{}\"", s)
+            }
+            1 => {
+                write!(f, "[ ")?;
+                for x in &self.d {
+                    write!(f, "{:>1$} ", x.to_string(), width)?;
+                }
+                write!(f, "]")
+            }
+            _ => {
+                write!(f, "[ ")?;
+                for (i, cell) in self.cells().iter().enumerate() {
+                    if i > 0 {
+                        write!(f, "\n{:1$}", "", indent + 2)?;
+                    }
+                    cell.fmt_nested(f, indent + 2, width)?;
+                }
+                write!(f, " ]")
+            }
         }
     }
 }
 
+impl fmt::Display for A {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Pad the elements of higher rank arrays to a common width so that
+        // columns line up.
+        let width = if self.rank() > 1 {
+            self.d.iter().map(|x| x.to_string().len()).max().unwrap_or(0)
+        } else {
+            0
+        };
+        self.fmt_nested(f, 0, width)
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum Prim {
     Num(f64),
     Char(char),
+    Boxed(Box<A>),
+    /// Code pushed by a quotation, shared between the copies of it.
+    Quote(Rc<Ast>),
 }
 
 impl Prim {
@@ -58,31 +196,28 @@ impl Prim {
     }
 
     pub fn eql(&self, rhs: &Self) -> Self {
-        use Prim::{Char, Num};
+        use Prim::{Boxed, Char, Num, Quote};
 
         match (self, rhs) {
             (Num(x), Num(y)) if x == y => Num(1.),
             (Char(x), Char(y)) if x == y => Num(1.),
+            (Boxed(x), Boxed(y)) if x == y => Num(1.),
+            (Quote(x), Quote(y)) if x == y => Num(1.),
             _ => Num(0.),
         }
     }
 
     pub fn eq_type(&self, rhs: &Self) -> bool {
-        use Prim::{Char, Num};
+        use Prim::{Boxed, Char, Num, Quote};
 
-        match (self, rhs) {
-            (Num(_), Num(_)) => true,
-            (Char(_), Char(_)) => true,
-            _ => false,
-        }
+        matches!(
+            (self, rhs),
+            (Num(_), Num(_)) | (Char(_), Char(_)) | (Boxed(_), Boxed(_)) | (Quote(_), Quote(_))
+        )
     }
 
     pub fn is_num(&self) -> bool {
-        if let Prim::Num(_) = self {
-            true
-        } else {
-            false
-        }
+        matches!(self, Prim::Num(_))
     }
 
     pub fn as_f64(&self) -> Option<f64> {
@@ -98,6 +233,29 @@ impl Prim {
             _ => None,
         }
     }
+
+    /// Take the array out of a box. Other values open to themselves as a scalar.
+    pub fn open(self) -> A {
+        match self {
+            Prim::Boxed(x) => *x,
+            x => A::scalar(x),
+        }
+    }
+}
+
+impl fmt::Display for Prim {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Prim::Num(x) => write!(f, "{}", x),
+            Prim::Char(x) => write!(f, "{:?}", x),
+            Prim::Boxed(x) => write!(f, "<{}>", x),
+            Prim::Quote(ast) => {
+                write!(f, "{{ ")?;
+                parser::write_ast(f, ast)?;
+                write!(f, "}}")
+            }
+        }
+    }
 }
 
 impl<'a> Add<&'a Prim> for &'a Prim {
@@ -163,3 +321,17 @@ impl<'a> Div<&'a Prim> for &'a Prim {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn try_new_checks_the_number_of_elements() {
+        let d = vec![Prim::Num(1.), Prim::Num(2.)];
+        assert_eq!(A::try_new(vec![2], d.clone()).unwrap(), A::from_nums(&[1., 2.]));
+        assert!(A::try_new(vec![3], d.clone()).is_err());
+        assert!(A::try_new(vec![], d).is_err());
+        assert!(A::try_new(vec![0, 5], vec![]).is_ok());
+    }
+}
@@ -1,15 +1,51 @@
-use crate::array::A;
+use std::rc::Rc;
+
+use crate::array::{Prim, A};
 use crate::errors::ErrorType;
+use crate::parser::{Ast, Builtins};
+
+/// Apply `f` between the elements of `a` and `b`. The shape of one argument
+/// must be a prefix of the other's, and each element of the shorter one is
+/// then repeated along the trailing axes of the longer, so scalars extend to
+/// any shape and a vector extends along the rows of a matrix.
+fn pervade<F>(a: &A, b: &A, f: F) -> Result<(Vec<usize>, Option<Vec<Prim>>), ErrorType>
+where
+    F: Fn(&Prim, &Prim) -> Option<Prim>,
+{
+    let shape = if a.rank() >= b.rank() { &a.shape } else { &b.shape };
+    if !shape.starts_with(&a.shape) || !shape.starts_with(&b.shape) {
+        return Err(ErrorType::Shape(a.shape.clone(), b.shape.clone()));
+    }
+
+    let n: usize = shape.iter().product();
+    let ra = n / a.d.len().max(1);
+    let rb = n / b.d.len().max(1);
+    let d = (0..n).map(|i| f(&a.d[i / ra], &b.d[i / rb])).collect();
+
+    Ok((shape.clone(), d))
+}
+
+/// The function the pervasive builtins apply between each pair of elements.
+pub fn kernel(b: &Builtins) -> Option<fn(&Prim, &Prim) -> Option<Prim>> {
+    use Builtins::*;
+
+    match b {
+        Plus => Some(|x, y| x + y),
+        Minus => Some(|x, y| x - y),
+        Multiply => Some(|x, y| x * y),
+        Divide => Some(|x, y| x / y),
+        And => Some(|x, y| x.and(y)),
+        Or => Some(|x, y| x.or(y)),
+        Equal => Some(|x, y| Some(x.eql(y))),
+        _ => None,
+    }
+}
 
 pub fn plus(a: A, b: A) -> Result<A, ErrorType> {
-    let d: Option<Vec<_>> =
-        a.d.iter()
-            .zip(b.d.iter().cycle())
-            .map(|(x, y)| x + y)
-            .collect();
+    let (shape, d) = pervade(&a, &b, |x, y| x + y)?;
 
     if let Some(d) = d {
-        Ok(A { rank: a.rank, d })
+        Ok(A::new(shape, d))
     } else {
         Err(ErrorType::Msg(
             "Couldn't add values, not all values were numbers."
@@ -18,14 +54,10 @@ pub fn plus(a: A, b: A) -> Result<A, ErrorType> {
 }
 
 pub fn minus(a: A, b: A) -> Result<A, ErrorType> {
-    let d: Option<Vec<_>> =
-        a.d.iter()
-            .zip(b.d.iter().cycle())
-            .map(|(x, y)| x - y)
-            .collect();
+    let (shape, d) = pervade(&a, &b, |x, y| x - y)?;
 
     if let Some(d) = d {
-        Ok(A { rank: a.rank, d })
+        Ok(A::new(shape, d))
     } else {
         Err(ErrorType::Msg(
             "Couldn't subtract values, not all values were numbers."
@@ -34,30 +66,22 @@ pub fn minus(a: A, b: A) -> Result<A, ErrorType> {
 }
 
 pub fn multiply(a: A, b: A) -> Result<A, ErrorType> {
-    let d: Option<Vec<_>> =
-        a.d.iter()
-            .zip(b.d.iter().cycle())
-            .map(|(x, y)| x * y)
-            .collect();
+    let (shape, d) = pervade(&a, &b, |x, y| x * y)?;
 
     if let Some(d) = d {
-        Ok(A { rank: a.rank, d })
+        Ok(A::new(shape, d))
     } else {
         Err(ErrorType::Msg(
-            "Couldn't divide values, not all values were numbers."
+            "Couldn't multiply values, not all values were numbers."
         ))
     }
 }
 
 pub fn divide(a: A, b: A) -> Result<A, ErrorType> {
-    let d: Option<Vec<_>> =
-        a.d.iter()
-            .zip(b.d.iter().cycle())
-            .map(|(x, y)| x / y)
-            .collect();
+    let (shape, d) = pervade(&a, &b, |x, y| x / y)?;
 
     if let Some(d) = d {
-        Ok(A { rank: a.rank, d })
+        Ok(A::new(shape, d))
     } else {
         Err(ErrorType::Msg(
             "Couldn't divide values, not all values were numbers."
@@ -66,68 +90,276 @@ pub fn divide(a: A, b: A) -> Result<A, ErrorType> {
 }
 
 pub fn and(a: A, b: A) -> Result<A, ErrorType> {
-    let d: Option<Vec<_>> =
-        a.d.iter()
-            .zip(b.d.iter().cycle())
-            .map(|(x, y)| x.and(y))
-            .collect();
+    let (shape, d) = pervade(&a, &b, |x, y| x.and(y))?;
 
     if let Some(d) = d {
-        Ok(A { rank: a.rank, d })
+        Ok(A::new(shape, d))
     } else {
         Err(ErrorType::Msg("Couldn't compare values."))
     }
 }
 
 pub fn or(a: A, b: A) -> Result<A, ErrorType> {
-    let d: Option<Vec<_>> =
-        a.d.iter()
-            .zip(b.d.iter().cycle())
-            .map(|(x, y)| x.or(y))
-            .collect();
+    let (shape, d) = pervade(&a, &b, |x, y| x.or(y))?;
 
     if let Some(d) = d {
-        Ok(A { rank: a.rank, d })
+        Ok(A::new(shape, d))
     } else {
         Err(ErrorType::Msg("Couldn't compare values."))
     }
 }
 
+/// An element is true if the sum of its values is positive.
 pub fn iff(a: A) -> Result<bool, ErrorType> {
-    if a.d.iter().fold(0., |acc, x| x + acc) > 0. {
-        Ok(false)
-    } else {
-        Ok(true)
-    }
+    Ok(a.d.iter().fold(0., |acc, x| x + acc) > 0.)
 }
 
 pub fn print(a: &A) {
-    if let Some(v) = a.d.first() {
-        if v.is_num() {
-            let fs: Option<Vec<_>> = a.d.iter().map(|x| x.as_f64()).collect();
-            println!("{:#?}", fs.unwrap());
+    println!("{}", a);
+}
+
+pub fn equal(a: A, b: A) -> Result<A, ErrorType> {
+    let (shape, d) = pervade(&a, &b, |x, y| Some(x.eql(y)))?;
+    Ok(A::new(shape, d.unwrap_or_default()))
+}
+
+/// Concatenate along the leading axis. Scalars and vectors join into a
+/// vector, otherwise the items of both arrays must have the same shape.
+pub fn concat(a: A, b: A) -> Result<A, ErrorType> {
+    if let (Some(x), Some(y)) = (a.d.first(), b.d.first()) {
+        if !x.eq_type(y) {
+            return Err(ErrorType::Msg("Mismatched types."));
+        }
+    }
+
+    // The array of lower rank counts as a single item of the other.
+    let item = |x: &A, other: &A| {
+        if x.rank() >= other.rank() {
+            x.shape.iter().skip(1).copied().collect::<Vec<_>>()
         } else {
-            let fs: Option<String> = a.d.iter().map(|x| x.as_char()).collect();
-            println!("{:#?}", fs.unwrap());
+            x.shape.clone()
         }
+    };
+    let (ia, ib) = (item(&a, &b), item(&b, &a));
+    if ia != ib {
+        return Err(ErrorType::Msg("Couldn't concatenate, item shapes differ."));
     }
+
+    let count = |x: &A| if x.rank() == ia.len() + 1 { x.len() } else { 1 };
+    let mut shape = vec![count(&a) + count(&b)];
+    shape.extend(ia);
+
+    Ok(A::new(shape, a.d.into_iter().chain(b.d).collect()))
+}
+
+/// Read the elements of `a` as non-negative integers, such as a shape or a
+/// list of axes.
+fn naturals(a: &A) -> Option<Vec<usize>> {
+    a.d.iter()
+        .map(|x| x.as_f64().filter(|x| *x >= 0. && x.fract() == 0.))
+        .map(|x| x.map(|x| x as usize))
+        .collect()
 }
 
-pub fn equal(a: A, b: A) -> A {
-    A { 
-        rank: a.rank,
-        d: a.d.iter().zip(b.d.iter()).map(|(x, y)| { x.eql(y) }).collect(),
+pub fn shape(a: &A) -> A {
+    let s: Vec<_> = a.shape.iter().map(|x| *x as f64).collect();
+    A::from_nums(&s)
+}
+
+/// Reshape `a` into the shape given by `s`, repeating the data of `a` from
+/// the start if the new shape holds more elements than it has.
+pub fn reshape(a: A, s: A) -> Result<A, ErrorType> {
+    let shape = naturals(&s).ok_or(ErrorType::Msg(
+        "Couldn't reshape, shape must be non-negative integers.",
+    ))?;
+
+    // More elements than could ever be allocated is an error rather than a
+    // panic.
+    let n = shape.iter().try_fold(1usize, |n, &len| n.checked_mul(len));
+    let n = n.filter(|&n| n <= isize::MAX as usize / std::mem::size_of::<Prim>());
+    let n = n.ok_or(ErrorType::Msg("Couldn't reshape, too many elements."))?;
+    if a.d.is_empty() && n > 0 {
+        return Err(ErrorType::Msg("Couldn't reshape, no elements to fill with."));
     }
+
+    Ok(A::new(shape, a.d.into_iter().cycle().take(n).collect()))
 }
 
-pub fn concat(a: A, b: A) -> Result<A, ErrorType> {
-    let x = a.d.first().ok_or(ErrorType::Msg("No elements in first argument array"))?;
-    let y = b.d.first().ok_or(ErrorType::Msg("No elements in first argument array"))?;
+pub fn ravel(a: A) -> A {
+    A::new(vec![a.d.len()], a.d)
+}
+
+/// Reverse the order of the axes of `a`.
+pub fn transpose(a: A) -> A {
+    let perm: Vec<_> = (0..a.rank()).rev().collect();
+    permute_axes(a, &perm)
+}
+
+/// Reorder the axes of `a` so that axis `i` of the result is axis `p[i]` of
+/// `a`.
+pub fn permute(a: A, p: A) -> Result<A, ErrorType> {
+    let perm = naturals(&p).ok_or(ErrorType::Msg(
+        "Couldn't permute, axes must be non-negative integers.",
+    ))?;
+
+    let mut sorted = perm.clone();
+    sorted.sort_unstable();
+    if !sorted.into_iter().eq(0..a.rank()) {
+        return Err(ErrorType::Msg(
+            "Couldn't permute, axes must be a permutation of the array's axes.",
+        ));
+    }
+
+    Ok(permute_axes(a, &perm))
+}
+
+fn permute_axes(a: A, perm: &[usize]) -> A {
+    let mut strides = vec![1; a.rank()];
+    for i in (1..a.rank()).rev() {
+        strides[i - 1] = strides[i] * a.shape[i];
+    }
+
+    let shape: Vec<_> = perm.iter().map(|&i| a.shape[i]).collect();
+    let steps: Vec<_> = perm.iter().map(|&i| strides[i]).collect();
+
+    let mut index = vec![0; shape.len()];
+    let mut d = Vec::with_capacity(a.d.len());
+    for _ in 0..a.d.len() {
+        let offset: usize = index.iter().zip(&steps).map(|(i, s)| i * s).sum();
+        d.push(a.d[offset].clone());
+
+        for axis in (0..shape.len()).rev() {
+            index[axis] += 1;
+            if index[axis] < shape[axis] {
+                break;
+            }
+            index[axis] = 0;
+        }
+    }
+
+    A::new(shape, d)
+}
+
+pub fn enclose(a: A) -> A {
+    A::scalar(Prim::Boxed(Box::new(a)))
+}
 
-    if !x.eq_type(y) { return Err(ErrorType::Msg("Mismatched types."))}
+/// Open the boxes of `a`. An array of boxes has to hold values of the same
+/// shape, which become the trailing axes of the result.
+pub fn disclose(a: A) -> Result<A, ErrorType> {
+    let cells = a.d.into_iter().map(Prim::open).collect();
+    A::merge(a.shape, cells).ok_or(ErrorType::Msg(
+        "Couldn't disclose, boxed values have different shapes.",
+    ))
+}
+
+/// Take the code out of a quotation.
+pub fn quotation(a: A) -> Result<Rc<Ast>, ErrorType> {
+    match a.d.into_iter().next() {
+        Some(Prim::Quote(ast)) if a.shape.is_empty() => Ok(ast),
+        _ => Err(ErrorType::Msg("Couldn't call, not a quotation.")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    Ok(A {
-        rank: a.rank,
-        d: a.d.into_iter().chain(b.d.into_iter()).map(|x| x).collect()
-    })
+    fn nums(shape: &[usize], xs: &[f64]) -> A {
+        A::new(shape.to_vec(), xs.iter().map(|x| Prim::Num(*x)).collect())
+    }
+
+    #[test]
+    fn scalars_extend_to_any_shape() {
+        let m = nums(&[2, 2], &[1., 2., 3., 4.]);
+        assert_eq!(plus(m.clone(), A::from_num(1.)).unwrap(), nums(&[2, 2], &[2., 3., 4., 5.]));
+        assert_eq!(minus(A::from_num(10.), m).unwrap(), nums(&[2, 2], &[9., 8., 7., 6.]));
+    }
+
+    #[test]
+    fn vectors_extend_along_rows() {
+        let m = nums(&[2, 3], &[1., 2., 3., 4., 5., 6.]);
+        let v = nums(&[2], &[10., 100.]);
+        let expected = nums(&[2, 3], &[10., 20., 30., 400., 500., 600.]);
+        assert_eq!(multiply(m.clone(), v.clone()).unwrap(), expected);
+        assert_eq!(multiply(v, m).unwrap(), expected);
+    }
+
+    #[test]
+    fn mismatched_shapes_are_an_error() {
+        let a = nums(&[2, 3], &[1., 2., 3., 4., 5., 6.]);
+        let b = nums(&[3], &[1., 2., 3.]);
+        match plus(a, b) {
+            Err(ErrorType::Shape(a, b)) => assert_eq!((a, b), (vec![2, 3], vec![3])),
+            r => panic!("expected a shape error, got {:?}", r),
+        }
+        assert!(matches!(
+            equal(nums(&[2], &[1., 2.]), nums(&[3], &[1., 2., 3.])),
+            Err(ErrorType::Shape(..))
+        ));
+    }
+
+    #[test]
+    fn empty_arrays_combine_with_scalars() {
+        let empty = nums(&[0], &[]);
+        assert_eq!(plus(empty.clone(), A::from_num(1.)).unwrap(), empty);
+    }
+
+    #[test]
+    fn reshape_repeats_the_elements() {
+        let v = nums(&[3], &[1., 2., 3.]);
+        let m = reshape(v.clone(), nums(&[2], &[2., 4.])).unwrap();
+        assert_eq!(m, nums(&[2, 4], &[1., 2., 3., 1., 2., 3., 1., 2.]));
+        assert_eq!(shape(&m), nums(&[2], &[2., 4.]));
+        assert_eq!(reshape(v, nums(&[1], &[2.])).unwrap(), nums(&[2], &[1., 2.]));
+    }
+
+    #[test]
+    fn reshape_to_an_empty_shape() {
+        let empty = reshape(nums(&[0], &[]), nums(&[2], &[0., 3.])).unwrap();
+        assert_eq!(empty, nums(&[0, 3], &[]));
+        assert_eq!(shape(&empty), nums(&[2], &[0., 3.]));
+        let m = reshape(nums(&[2], &[1., 2.]), nums(&[2], &[3., 0.])).unwrap();
+        assert_eq!(m.shape, vec![3, 0]);
+    }
+
+    #[test]
+    fn reshape_errors() {
+        let empty = nums(&[0], &[]);
+        assert!(reshape(empty, nums(&[1], &[2.])).is_err());
+        assert!(reshape(A::from_num(1.), nums(&[1], &[-1.])).is_err());
+        assert!(reshape(A::from_num(1.), nums(&[2], &[1e10, 1e10])).is_err());
+        assert!(reshape(nums(&[2], &[1., 2.]), nums(&[1], &[1e19])).is_err());
+    }
+
+    #[test]
+    fn ravel_flattens() {
+        let m = nums(&[2, 3], &[1., 2., 3., 4., 5., 6.]);
+        assert_eq!(ravel(m), nums(&[6], &[1., 2., 3., 4., 5., 6.]));
+        assert_eq!(ravel(A::from_num(1.)), nums(&[1], &[1.]));
+    }
+
+    #[test]
+    fn transpose_reverses_axes() {
+        let m = nums(&[2, 3], &[1., 2., 3., 4., 5., 6.]);
+        assert_eq!(transpose(m), nums(&[3, 2], &[1., 4., 2., 5., 3., 6.]));
+        let v = nums(&[3], &[1., 2., 3.]);
+        assert_eq!(transpose(v.clone()), v);
+    }
+
+    #[test]
+    fn permute_moves_axes() {
+        let a = nums(&[2, 1, 3], &[1., 2., 3., 4., 5., 6.]);
+        let p = permute(a.clone(), nums(&[3], &[2., 0., 1.])).unwrap();
+        assert_eq!(p, nums(&[3, 2, 1], &[1., 4., 2., 5., 3., 6.]));
+        assert_eq!(permute(a.clone(), nums(&[3], &[0., 1., 2.])).unwrap(), a);
+    }
+
+    #[test]
+    fn permute_needs_a_permutation() {
+        let a = nums(&[2, 3], &[1., 2., 3., 4., 5., 6.]);
+        assert!(permute(a.clone(), nums(&[2], &[0., 0.])).is_err());
+        assert!(permute(a.clone(), nums(&[1], &[0.])).is_err());
+        assert!(permute(a, nums(&[2], &[1.5, 0.])).is_err());
+    }
 }
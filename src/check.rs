@@ -0,0 +1,334 @@
+use std::collections::HashMap;
+
+use crate::array::{Prim, A};
+use crate::errors::{Error, ErrorType};
+use crate::eval::Native;
+use crate::parser::{Ast, Builtins, Effect, Node, Token};
+
+/// The values the checker sees on the stack. Literals are known, anything
+/// computed is `None`.
+type Value<'a> = Option<&'a A>;
+
+/// Why checking a piece of code stopped early: either its effect depends on
+/// values only known when it runs, or it is certainly wrong, such as
+/// underflowing or using a word that isn't defined.
+type Stop = Option<Error>;
+
+/// The stack while walking through a piece of code.
+#[derive(Clone, Default)]
+struct State<'a> {
+    /// The number of values taken from below where the code started.
+    ins: usize,
+    /// The values the code has left so far.
+    stack: Vec<Value<'a>>,
+}
+
+struct Checker<'a> {
+    words: &'a HashMap<String, Ast>,
+    natives: &'a HashMap<String, Native>,
+    /// The annotations of the words defined in the code being checked.
+    declared: HashMap<&'a str, &'a Effect>,
+    /// The effects inferred for words so far, `None` if they can't be known.
+    inferred: HashMap<&'a str, Option<(usize, usize)>>,
+    /// The words currently being inferred, to give up on recursive words.
+    pending: Vec<&'a str>,
+    /// Whether the code being walked is the body of an annotated definition,
+    /// where the branches of conditionals have to agree.
+    annotated: bool,
+}
+
+/// Check the stack effects of `ast` before running it on a stack of `depth`
+/// values. Definitions have to agree with their annotations, and so have the
+/// branches of the conditionals in them, every word used has to be defined,
+/// and the code itself mustn't take more values than the stack will have. Code whose effect depends on the values it runs on, like
+/// calling a quotation that was computed, is given the benefit of the doubt.
+pub fn check(
+    ast: &Ast,
+    words: &HashMap<String, Ast>,
+    natives: &HashMap<String, Native>,
+    depth: usize,
+) -> Vec<Error> {
+    let mut checker = Checker {
+        words,
+        natives,
+        declared: HashMap::new(),
+        inferred: HashMap::new(),
+        pending: vec![],
+        annotated: false,
+    };
+    for node in ast {
+        if let Token::Definition((name, _, Some(effect))) = &node.token {
+            checker.declared.insert(name, effect);
+        }
+    }
+
+    let mut errors = vec![];
+    for node in ast {
+        if let Token::Definition((name, body, effect)) = &node.token {
+            // Taking fewer values than declared is fine as long as the body
+            // leaves correspondingly fewer.
+            let mut state = State::default();
+            let limit = effect.as_ref().map(|e| e.ins.len());
+            checker.annotated = effect.is_some();
+            match (checker.walk(body, &mut state, limit), effect) {
+                (Ok(()), Some(effect))
+                    if state.stack.len() + effect.ins.len() != effect.outs.len() + state.ins =>
+                {
+                    let inferred = (state.ins, state.stack.len());
+                    let ty = ErrorType::Effect(name.clone(), effect.clone(), inferred);
+                    errors.push(Error::new(ty, &node.span, name));
+                }
+                (Err(Some(e)), _) => errors.push(e),
+                _ => {}
+            }
+        }
+    }
+
+    checker.annotated = false;
+    if let Err(Some(e)) = checker.walk(ast, &mut State::default(), Some(depth)) {
+        errors.push(e);
+    }
+
+    errors
+}
+
+impl<'a> Checker<'a> {
+    /// The effect of calling the word `name`. Annotated words are trusted to
+    /// match their annotation, as their definition is checked on its own.
+    fn word(&mut self, name: &'a str) -> Option<(usize, usize)> {
+        if let Some(effect) = self.declared.get(name) {
+            return Some((effect.ins.len(), effect.outs.len()));
+        }
+        if let Some(effect) = self.inferred.get(name) {
+            return *effect;
+        }
+        if self.pending.contains(&name) {
+            return None;
+        }
+
+        let body = match self.words.get(name) {
+            Some(body) => body,
+            None => return self.natives.get(name).map(|n| (n.ins, n.outs)),
+        };
+        // Errors in the body are reported when checking its definition.
+        self.pending.push(name);
+        let effect = self.infer(body).ok();
+        self.pending.pop();
+        self.inferred.insert(name, effect);
+        effect
+    }
+
+    /// The values `ast` takes and leaves.
+    fn infer(&mut self, ast: &'a Ast) -> Result<(usize, usize), Stop> {
+        let mut state = State::default();
+        self.walk(ast, &mut state, None)?;
+        Ok((state.ins, state.stack.len()))
+    }
+
+    /// Follow `ast` through `state`. Code that takes more than `limit` values
+    /// from below where it started will certainly underflow.
+    fn walk(
+        &mut self,
+        ast: &'a Ast,
+        state: &mut State<'a>,
+        limit: Option<usize>,
+    ) -> Result<(), Stop> {
+        for node in ast {
+            self.step(node, state, limit)?;
+        }
+        Ok(())
+    }
+
+    fn step(
+        &mut self,
+        node: &'a Node,
+        state: &mut State<'a>,
+        limit: Option<usize>,
+    ) -> Result<(), Stop> {
+        use Token::*;
+
+        match &node.token {
+            Word(name) => {
+                let defined = self.declared.contains_key(name.as_str())
+                    || self.words.contains_key(name)
+                    || self.natives.contains_key(name);
+                if !defined {
+                    let ty = ErrorType::Undefined(name.clone());
+                    return Err(Some(Error::new(ty, &node.span, name)));
+                }
+                let (ins, outs) = self.word(name).ok_or(None)?;
+                take(state, ins, node, name, limit)?;
+                push_unknown(state, outs);
+            }
+            Data(a) => state.stack.push(Some(a)),
+            Builtin(b) => self.builtin(b, node, state, limit)?,
+            If((t, f)) => {
+                take(state, 1, node, "if", limit)?;
+                let (t, f) = (self.infer(t)?, self.infer(f)?);
+                if t.1 as isize - t.0 as isize != f.1 as isize - f.0 as isize {
+                    if !self.annotated {
+                        return Err(None);
+                    }
+                    let ty = ErrorType::Msg("The branches of `if` leave different numbers of values.");
+                    return Err(Some(Error::new(ty, &node.span, "if")));
+                }
+                let (ins, outs) = if t.0 >= f.0 { t } else { f };
+                take(state, ins, node, "if", limit)?;
+                push_unknown(state, outs);
+            }
+            Do(body) => {
+                take(state, 1, node, "do", limit)?;
+                let ins = self.balanced(body, 0)?;
+                take(state, ins, node, "do", limit)?;
+                push_unknown(state, ins);
+            }
+            While((cond, body)) => {
+                let ins = self.balanced(cond, 1)?.max(self.balanced(body, 0)?);
+                take(state, ins, node, "while", limit)?;
+                push_unknown(state, ins);
+            }
+            Until(body) => {
+                let ins = self.balanced(body, 1)?;
+                take(state, ins, node, "until", limit)?;
+                push_unknown(state, ins);
+            }
+            Definition(_) => {}
+        }
+
+        Ok(())
+    }
+
+    fn builtin(
+        &mut self,
+        b: &Builtins,
+        node: &'a Node,
+        state: &mut State<'a>,
+        limit: Option<usize>,
+    ) -> Result<(), Stop> {
+        if let Some((ins, outs)) = b.effect() {
+            take(state, ins, node, b.name(), limit)?;
+            push_unknown(state, outs);
+            return Ok(());
+        }
+
+        match b {
+            // Calling a literal quotation is the same as writing its code out.
+            Builtins::Call => match take(state, 1, node, b.name(), limit)?[0] {
+                Some(A { shape, d }) if shape.is_empty() => match &d[0] {
+                    Prim::Quote(ast) => self.walk(ast, state, limit),
+                    _ => Err(None),
+                },
+                _ => Err(None),
+            },
+            // With literal ranks, `rnk` takes one argument per rank.
+            Builtins::Rank => match take(state, 2, node, b.name(), limit)?[1] {
+                Some(k) => {
+                    take(state, k.d.len(), node, b.name(), limit)?;
+                    push_unknown(state, 1);
+                    Ok(())
+                }
+                None => Err(None),
+            },
+            _ => Err(None),
+        }
+    }
+
+    /// The values a loop body takes, if it leaves as many as it takes plus
+    /// `flags` to test.
+    fn balanced(&mut self, body: &'a Ast, flags: usize) -> Result<usize, Stop> {
+        match self.infer(body)? {
+            (ins, outs) if outs == ins + flags => Ok(ins),
+            _ => Err(None),
+        }
+    }
+}
+
+/// Take `n` values off the stack for `node`, reaching below where the code
+/// started if there aren't enough.
+fn take<'a>(
+    state: &mut State<'a>,
+    n: usize,
+    node: &Node,
+    name: &str,
+    limit: Option<usize>,
+) -> Result<Vec<Value<'a>>, Stop> {
+    let have = state.stack.len();
+    if n <= have {
+        return Ok(state.stack.split_off(have - n));
+    }
+
+    let ins = state.ins + n - have;
+    if let Some(limit) = limit {
+        if ins > limit {
+            let len = have + limit - state.ins;
+            let ty = ErrorType::Underflow(name.to_string(), n, len);
+            return Err(Some(Error::new(ty, &node.span, name)));
+        }
+    }
+
+    state.ins = ins;
+    let mut values = vec![None; n - have];
+    values.append(&mut state.stack);
+    Ok(values)
+}
+
+fn push_unknown(state: &mut State, n: usize) {
+    state.stack.extend((0..n).map(|_| None));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser;
+
+    /// The problems found in `src` when it runs on an empty stack.
+    fn errors(src: &str) -> Vec<ErrorType> {
+        let (ast, words) = parser::parse(src, "").unwrap();
+        check(&ast, &words, &HashMap::new(), 0).into_iter().map(|e| e.ty).collect()
+    }
+
+    #[test]
+    fn accepts_code_that_agrees_with_its_annotations() {
+        for src in [
+            ": sq ( a -- b ) dup * ; 2 sq",
+            ": pick ( a -- b ) if 1 else 2 then ;",
+            ": down ( a -- b ) dup if 1 - down then ;",
+            ": sum ( a -- b ) 0 swp do i + loop ; 3 sum",
+            "1 2 { + } call",
+            "{ 1 } dup call call",
+            ": later 1 ; later",
+            "1 if 1 then",
+            ": maybe if 1 else 2 3 then ; 0 maybe",
+        ] {
+            assert!(errors(src).is_empty(), "`{}` was rejected", src);
+        }
+    }
+
+    #[test]
+    fn rejects_annotations_that_disagree() {
+        let errs = errors(": sq ( a -- b ) dup dup * ;");
+        assert!(matches!(&errs[..], [ErrorType::Effect(w, _, (1, 2))] if w == "sq"));
+    }
+
+    #[test]
+    fn rejects_unbalanced_branches() {
+        let errs = errors(": f ( a -- b ) if 1 else 2 3 then ;");
+        assert!(matches!(&errs[..], [ErrorType::Msg(_)]));
+        let errs = errors(": f ( a -- b ) dup if if 1 then then ;");
+        assert!(matches!(&errs[..], [ErrorType::Msg(_)]));
+    }
+
+    #[test]
+    fn rejects_underflow() {
+        assert!(matches!(&errors("1 +")[..], [ErrorType::Underflow(w, 2, 1)] if w == "+"));
+        let errs = errors(": sq ( a -- b ) dup * ; sq");
+        assert!(matches!(&errs[..], [ErrorType::Underflow(w, 1, 0)] if w == "sq"));
+    }
+
+    #[test]
+    fn rejects_undefined_words() {
+        assert!(matches!(&errors("1 foo")[..], [ErrorType::Undefined(w)] if w == "foo"));
+        let errs = errors(": g 1 bar ;");
+        assert!(matches!(&errs[..], [ErrorType::Undefined(w)] if w == "bar"));
+    }
+}
@@ -0,0 +1,285 @@
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+use crossterm::{
+    cursor,
+    event::{self, Event, KeyCode, KeyModifiers},
+    queue, terminal, Result,
+};
+
+/// The most lines of history to keep.
+const HISTORY_LIMIT: usize = 1000;
+
+/// What reading a line ended with.
+pub enum Input {
+    Line(String),
+    /// Ctrl-C on an empty line, to drop what was read before it.
+    Cancel,
+    /// Ctrl-D on an empty line.
+    End,
+}
+
+/// A line being edited, with the cursor as an index into its characters.
+#[derive(Default)]
+pub struct Line {
+    pub chars: Vec<char>,
+    pub cursor: usize,
+}
+
+impl Line {
+    pub fn text(&self) -> String {
+        self.chars.iter().collect()
+    }
+
+    /// Replace the contents of the line, leaving the cursor at the end.
+    pub fn set(&mut self, s: &str) {
+        self.chars = s.chars().collect();
+        self.cursor = self.chars.len();
+    }
+
+    pub fn insert(&mut self, c: char) {
+        self.chars.insert(self.cursor, c);
+        self.cursor += 1;
+    }
+
+    /// Where the word before the cursor starts, skipping any whitespace
+    /// right before it.
+    pub fn word_start(&self) -> usize {
+        let before = &self.chars[..self.cursor];
+        let end = before.iter().rposition(|c| !c.is_whitespace()).map_or(0, |i| i + 1);
+        before[..end].iter().rposition(|c| c.is_whitespace()).map_or(0, |i| i + 1)
+    }
+
+    /// Where the run of non-whitespace characters up to the cursor starts.
+    pub fn token_start(&self) -> usize {
+        let before = &self.chars[..self.cursor];
+        before.iter().rposition(|c| c.is_whitespace()).map_or(0, |i| i + 1)
+    }
+
+    /// Replace the characters from `from` up to the cursor with `s`.
+    pub fn replace(&mut self, from: usize, s: &str) {
+        self.chars.splice(from..self.cursor, s.chars());
+        self.cursor = from + s.chars().count();
+    }
+
+    fn delete(&mut self, from: usize, to: usize) {
+        self.chars.drain(from..to);
+        self.cursor = from;
+    }
+}
+
+/// A reverse search through the history.
+struct Search {
+    query: String,
+    /// The index of the entry the query matched.
+    found: Option<usize>,
+}
+
+/// Reads lines from the terminal with the usual editing keys, and keeps a
+/// history of them that is saved between sessions.
+pub struct Editor {
+    history: Vec<String>,
+    path: Option<PathBuf>,
+}
+
+impl Editor {
+    /// Create an editor with the history saved in `~/.nd_history`.
+    pub fn new() -> Self {
+        let path = env::var_os("HOME").map(|home| PathBuf::from(home).join(".nd_history"));
+        let history = path
+            .as_ref()
+            .and_then(|p| fs::read_to_string(p).ok())
+            .map(|s| s.lines().map(String::from).collect::<Vec<_>>())
+            .unwrap_or_default();
+
+        let mut editor = Self { history, path };
+        if editor.history.len() > HISTORY_LIMIT {
+            editor.history.drain(..editor.history.len() - HISTORY_LIMIT);
+            if let Some(path) = &editor.path {
+                let _ = fs::write(path, editor.history.join("\n") + "\n");
+            }
+        }
+        editor
+    }
+
+    /// Add `line` to the history, unless it's empty or the same as the last.
+    pub fn add_history(&mut self, line: &str) {
+        if line.trim().is_empty() || self.history.last().map(String::as_str) == Some(line) {
+            return;
+        }
+        self.history.push(line.to_string());
+
+        if let Some(path) = &self.path {
+            let file = OpenOptions::new().create(true).append(true).open(path);
+            if let Ok(mut file) = file {
+                let _ = writeln!(file, "{}", line);
+            }
+        }
+    }
+
+    /// Read a line after showing `prompt`.
+    /// Tab calls `on_tab` with the line, which returns whether it printed
+    /// anything, in which case the line is shown again below. The line is
+    /// shown as `highlight` styles it.
+    pub fn read_line(
+        &mut self,
+        prompt: &str,
+        on_tab: &mut dyn FnMut(&mut Line) -> bool,
+        highlight: &dyn Fn(&str) -> String,
+    ) -> Result<Input> {
+        terminal::enable_raw_mode()?;
+        let r = self.edit(prompt, on_tab, highlight);
+        terminal::disable_raw_mode()?;
+        println!();
+        r
+    }
+
+    fn edit(
+        &mut self,
+        prompt: &str,
+        on_tab: &mut dyn FnMut(&mut Line) -> bool,
+        highlight: &dyn Fn(&str) -> String,
+    ) -> Result<Input> {
+        let mut line = Line::default();
+        // The position in the history, and the line being written before
+        // moving through it.
+        let mut pos = self.history.len();
+        let mut draft = String::new();
+        let mut search: Option<Search> = None;
+
+        draw(prompt, &line, highlight)?;
+        loop {
+            let key = match event::read()? {
+                Event::Key(key) => key,
+                _ => continue,
+            };
+
+            let control = key.modifiers.contains(KeyModifiers::CONTROL);
+            if let Some(s) = &mut search {
+                match (key.code, control) {
+                    (KeyCode::Char('r'), true) => {
+                        let before = s.found.unwrap_or(self.history.len());
+                        if let Some(i) = self.find(&s.query, before) {
+                            s.found = Some(i);
+                        }
+                    }
+                    (KeyCode::Char(c), false) => {
+                        s.query.push(c);
+                        let before = s.found.map_or(self.history.len(), |i| i + 1);
+                        s.found = self.find(&s.query, before);
+                    }
+                    (KeyCode::Backspace, _) => {
+                        s.query.pop();
+                        s.found = self.find(&s.query, self.history.len());
+                    }
+                    (KeyCode::Esc, _) => search = None,
+                    // Any other key takes the match into the line.
+                    _ => {
+                        if let Some(i) = s.found {
+                            line.set(&self.history[i]);
+                            pos = i;
+                        }
+                        search = None;
+                    }
+                }
+                match &search {
+                    Some(s) => draw_search(s, &self.history, highlight)?,
+                    None => draw(prompt, &line, highlight)?,
+                }
+                continue;
+            }
+
+            match (key.code, control) {
+                (KeyCode::Enter, _) => return Ok(Input::Line(line.text())),
+                (KeyCode::Char('d'), true) if line.chars.is_empty() => return Ok(Input::End),
+                (KeyCode::Char('c'), true) if line.chars.is_empty() => return Ok(Input::Cancel),
+                (KeyCode::Char('c'), true) => line = Line::default(),
+                (KeyCode::Char('a'), true) | (KeyCode::Home, _) => line.cursor = 0,
+                (KeyCode::Char('e'), true) | (KeyCode::End, _) => line.cursor = line.chars.len(),
+                (KeyCode::Char('w'), true) => line.delete(line.word_start(), line.cursor),
+                (KeyCode::Char('u'), true) => line.delete(0, line.cursor),
+                (KeyCode::Char('r'), true) => {
+                    let s = Search {
+                        query: String::new(),
+                        found: None,
+                    };
+                    draw_search(&s, &self.history, highlight)?;
+                    search = Some(s);
+                    continue;
+                }
+                (KeyCode::Char(c), false) => line.insert(c),
+                (KeyCode::Backspace, _) if line.cursor > 0 => {
+                    line.delete(line.cursor - 1, line.cursor)
+                }
+                (KeyCode::Delete, _) if line.cursor < line.chars.len() => {
+                    line.delete(line.cursor, line.cursor + 1)
+                }
+                (KeyCode::Left, _) if line.cursor > 0 => line.cursor -= 1,
+                (KeyCode::Right, _) if line.cursor < line.chars.len() => line.cursor += 1,
+                (KeyCode::Up, _) if pos > 0 => {
+                    if pos == self.history.len() {
+                        draft = line.text();
+                    }
+                    pos -= 1;
+                    line.set(&self.history[pos]);
+                }
+                (KeyCode::Down, _) if pos < self.history.len() => {
+                    pos += 1;
+                    match self.history.get(pos) {
+                        Some(entry) => line.set(entry),
+                        None => line.set(&draft),
+                    }
+                }
+                (KeyCode::Tab, _) => {
+                    terminal::disable_raw_mode()?;
+                    let printed = on_tab(&mut line);
+                    terminal::enable_raw_mode()?;
+                    if printed {
+                        print!("\r");
+                    }
+                }
+                _ => {}
+            }
+            draw(prompt, &line, highlight)?;
+        }
+    }
+
+    /// The most recent entry before `before` that contains `query`.
+    fn find(&self, query: &str, before: usize) -> Option<usize> {
+        self.history[..before.min(self.history.len())]
+            .iter()
+            .rposition(|entry| entry.contains(query))
+    }
+}
+
+/// Show `prompt` and `line` on the current row of the terminal, with the
+/// cursor in its place.
+fn draw(prompt: &str, line: &Line, highlight: &dyn Fn(&str) -> String) -> Result<()> {
+    let mut out = io::stdout();
+    queue!(
+        out,
+        cursor::MoveToColumn(1),
+        terminal::Clear(terminal::ClearType::UntilNewLine)
+    )?;
+    write!(out, "{}{}", prompt, highlight(&line.text()))?;
+    // Columns count from 1.
+    let column = prompt.chars().count() + line.cursor + 1;
+    queue!(out, cursor::MoveToColumn(column as u16))?;
+    out.flush()?;
+    Ok(())
+}
+
+fn draw_search(
+    search: &Search,
+    history: &[String],
+    highlight: &dyn Fn(&str) -> String,
+) -> Result<()> {
+    let found = search.found.map_or("", |i| history[i].as_str());
+    let line = Line {
+        chars: found.chars().collect(),
+        cursor: found.chars().count(),
+    };
+    draw(&format!("(reverse-i-search)`{}': ", search.query), &line, highlight)
+}
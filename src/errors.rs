@@ -1,21 +1,215 @@
+use std::collections::HashMap;
 use std::error;
 use std::fmt;
+use std::rc::Rc;
+
+use crate::parser::Effect;
 
 #[derive(Debug)]
 pub enum ErrorType {
     Parse,
+    Incomplete,
     Eval,
     Msg(&'static str),
+    Shape(Vec<usize>, Vec<usize>),
+    Undefined(String),
+    /// A word needed more values than the stack had, as the word, the values
+    /// it needed and the values there were.
+    Underflow(String, usize, usize),
+    /// A definition whose body doesn't match its stack effect annotation, as
+    /// the word, its annotation and the values its body takes and leaves.
+    Effect(String, Effect, (usize, usize)),
+    /// An error reported by a native word.
+    Native(String),
 }
 
 impl fmt::Display for ErrorType {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             ErrorType::Parse => write!(f, "Couldn't parse"),
+            ErrorType::Incomplete => write!(f, "Unexpected end of input"),
             ErrorType::Eval => write!(f, "Couldn't eval"),
             ErrorType::Msg(m) => write!(f, "{}", m),
+            ErrorType::Shape(a, b) => write!(f, "Shapes {:?} and {:?} don't agree.", a, b),
+            ErrorType::Undefined(w) => write!(f, "Undefined word `{}`.", w),
+            ErrorType::Underflow(w, n, len) => {
+                let values = if *n == 1 { "value" } else { "values" };
+                write!(f, "`{}` needs {} {}, stack has {}.", w, n, values, len)
+            }
+            ErrorType::Effect(w, effect, (ins, outs)) => write!(
+                f,
+                "`{}` is declared {} but takes {} and leaves {}.",
+                w, effect, ins, outs
+            ),
+            ErrorType::Native(m) => write!(f, "{}", m),
         }
     }
 }
 
 impl error::Error for ErrorType {}
+
+/// A stretch of source code, with lines and columns counted from 1.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Span {
+    pub file: Rc<str>,
+    pub line: usize,
+    pub col: usize,
+    /// Length in characters, if the span ends on the line it starts on.
+    pub len: usize,
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.line, self.col)
+    }
+}
+
+/// The token an error happened at.
+#[derive(Debug)]
+pub struct Location {
+    pub span: Span,
+    /// The source of the token.
+    pub token: String,
+}
+
+/// An error along with where it happened and how evaluation got there.
+#[derive(Debug)]
+pub struct Error {
+    pub ty: ErrorType,
+    /// Boxed, as errors are passed around far more often than they are shown.
+    pub location: Option<Box<Location>>,
+    /// The words and loops the error passed through, innermost first.
+    pub trace: Vec<(String, Span)>,
+}
+
+impl Error {
+    pub fn new(ty: ErrorType, span: &Span, token: &str) -> Self {
+        Self::from(ty).at(span, token)
+    }
+
+    /// Locate the error at `token`, or if it already has a location, add
+    /// `token` to its trace instead.
+    pub fn at(mut self, span: &Span, token: &str) -> Self {
+        if self.location.is_none() {
+            self.location = Some(Box::new(Location {
+                span: span.clone(),
+                token: token.to_string(),
+            }));
+        } else {
+            self.trace.push((format!("`{}`", token), span.clone()));
+        }
+        self
+    }
+
+    /// Add a step to the trace of the error.
+    pub fn within(mut self, frame: String, span: &Span) -> Self {
+        self.trace.push((frame, span.clone()));
+        self
+    }
+
+    /// Render the error with the offending line of source underlined, looking
+    /// up the source of each file in `sources`.
+    pub fn render(&self, sources: &HashMap<String, String>) -> String {
+        self.report("error", sources)
+    }
+
+    /// Render the error as a warning about code that may still run.
+    pub fn render_warning(&self, sources: &HashMap<String, String>) -> String {
+        self.report("warning", sources)
+    }
+
+    fn report(&self, level: &str, sources: &HashMap<String, String>) -> String {
+        let mut out = format!("{}: {}", level, self.ty);
+
+        if let Some(Location { span, token }) = self.location.as_deref() {
+            out += &format!("\n --> {}", span);
+
+            let line = sources
+                .get(&*span.file)
+                .and_then(|s| s.lines().nth(span.line - 1));
+            if let Some(line) = line {
+                let number = span.line.to_string();
+                let pad = " ".repeat(number.len());
+                let len = span.len.clamp(1, line.chars().count().saturating_sub(span.col - 1).max(1));
+                out += &format!("\n{} |", pad);
+                out += &format!("\n{} | {}", number, line);
+                out += &format!("\n{} | {}{}", pad, " ".repeat(span.col - 1), "^".repeat(len));
+            }
+            out += &format!(" in `{}`", token);
+        }
+
+        for (frame, span) in &self.trace {
+            out += &format!("\n  = in {} at {}", frame, span);
+        }
+
+        out
+    }
+}
+
+impl From<ErrorType> for Error {
+    fn from(ty: ErrorType) -> Self {
+        Self {
+            ty,
+            location: None,
+            trace: vec![],
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.render(&HashMap::new()))
+    }
+}
+
+impl error::Error for Error {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn span(line: usize, col: usize, len: usize) -> Span {
+        Span {
+            file: Rc::from("a.ff"),
+            line,
+            col,
+            len,
+        }
+    }
+
+    #[test]
+    fn render_underlines_the_token() {
+        let sources = HashMap::from([("a.ff".to_string(), "1 2\n: f\n  swp foo ;\n3 f".to_string())]);
+        let e = Error::new(ErrorType::Undefined("foo".to_string()), &span(3, 7, 3), "foo")
+            .at(&span(4, 3, 1), "f")
+            .within("iteration 2 of `do`".to_string(), &span(10, 1, 2));
+        let expected = [
+            "error: Undefined word `foo`.",
+            " --> a.ff:3:7",
+            "  |",
+            "3 |   swp foo ;",
+            "  |       ^^^ in `foo`",
+            "  = in `f` at a.ff:4:3",
+            "  = in iteration 2 of `do` at a.ff:10:1",
+        ];
+        assert_eq!(e.render(&sources), expected.join("\n"));
+        assert!(e.render_warning(&sources).starts_with("warning: Undefined word `foo`.\n"));
+    }
+
+    #[test]
+    fn render_keeps_the_underline_on_the_line() {
+        let sources = HashMap::from([("a.ff".to_string(), "1 [ 2\n3 ]".to_string())]);
+        let e = Error::new(ErrorType::Parse, &span(1, 3, 40), "[");
+        assert!(e.render(&sources).ends_with("\n  |   ^^^ in `[`"));
+        let e = Error::new(ErrorType::Parse, &span(1, 1, 0), "1");
+        assert!(e.render(&sources).ends_with("\n  | ^ in `1`"));
+    }
+
+    #[test]
+    fn render_without_the_source() {
+        let e = Error::new(ErrorType::Eval, &span(12, 4, 2), "+");
+        assert_eq!(e.render(&HashMap::new()), "error: Couldn't eval\n --> a.ff:12:4 in `+`");
+        let e = Error::from(ErrorType::Eval);
+        assert_eq!(e.render(&HashMap::new()), "error: Couldn't eval");
+    }
+}
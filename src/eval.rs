@@ -1,17 +1,59 @@
 use crate::array::A;
 use crate::builtins;
-use crate::{errors::ErrorType, parser::{Ast, Builtins, Token}};
+use crate::errors::{Error, ErrorType};
+use crate::parser::{Ast, Builtins, Node, Token};
+use crate::vm::Vm;
 use std::collections::HashMap;
+use std::rc::Rc;
 
-enum ControlFlow {
-    SkipToThen,
-    Continue,
-    Repeat(usize),
+/// The function behind a native word.
+type NativeFn = dyn Fn(Vec<A>) -> Result<Vec<A>, String>;
+
+/// A word implemented by the program embedding the interpreter, taking `ins`
+/// values from the stack and leaving `outs` values in their place.
+pub struct Native {
+    pub ins: usize,
+    pub outs: usize,
+    f: Box<NativeFn>,
+}
+
+impl Native {
+    /// Wrap `f`, which is given the values the word takes, deepest first, and
+    /// returns the values it leaves.
+    pub fn new<F>(ins: usize, outs: usize, f: F) -> Self
+    where
+        F: Fn(Vec<A>) -> Result<Vec<A>, String> + 'static,
+    {
+        Self {
+            ins,
+            outs,
+            f: Box::new(f),
+        }
+    }
+
+    pub(crate) fn eval(&self, name: &str, stack: &mut Vec<A>) -> Result<(), Error> {
+        if stack.len() < self.ins {
+            return Err(ErrorType::Underflow(name.to_string(), self.ins, stack.len()).into());
+        }
+
+        let args = stack.split_off(stack.len() - self.ins);
+        let results = (self.f)(args).map_err(ErrorType::Native)?;
+        if results.len() != self.outs {
+            return Err(ErrorType::Msg("Native word left the wrong number of values.").into());
+        }
+        stack.extend(results);
+        Ok(())
+    }
 }
 
 impl Builtins {
-    fn eval(&self, stack: &mut Vec<A>) -> Result<ControlFlow, ErrorType> {
+    pub(crate) fn eval(&self, stack: &mut Vec<A>, vm: &mut Vm) -> Result<(), Error> {
         use Builtins::*;
+
+        if stack.len() < self.arity() {
+            return Err(ErrorType::Underflow(self.name().to_string(), self.arity(), stack.len()).into());
+        }
+
         match self {
             Print => {
                 if let Some((last, _)) = stack.split_last() {
@@ -29,7 +71,7 @@ impl Builtins {
             Equal => {
                 let n1 = stack.pop().ok_or(ErrorType::Eval)?;
                 let n2 = stack.pop().ok_or(ErrorType::Eval)?;
-                let r = builtins::equal(n1, n2);
+                let r = builtins::equal(n2, n1)?;
                 stack.push(r);
             }
 
@@ -54,23 +96,9 @@ impl Builtins {
                 stack.push(r);
             }
 
-            If => {
-                let a = stack.pop().ok_or(ErrorType::Eval)?;
-                let c = builtins::iff(a)?;
-                if c {
-                    return Ok(ControlFlow::SkipToThen);
-                }
-            }
-
-            Do => {
-                let a = stack.pop().ok_or(ErrorType::Eval)?;
-                let x =
-                    a.d.first()
-                        .map(|x| x.as_f64())
-                        .flatten()
-                        .ok_or(ErrorType::Eval)?;
-
-                return Ok(ControlFlow::Repeat(x as usize));
+            Index => {
+                let i = vm.index().ok_or(ErrorType::Msg("Can't use i outside a loop."))?;
+                stack.push(A::from_num(i as f64));
             }
 
             And => {
@@ -96,16 +124,113 @@ impl Builtins {
 
             Len => {
                 let a = stack.pop().ok_or(ErrorType::Eval)?;
-                let l = a.d.len();
+                let l = a.len();
                 stack.push(a);
                 stack.push(A::from_num(l as f64));
             }
 
-            Transmute => {
+            Shape => {
                 let a = stack.pop().ok_or(ErrorType::Eval)?;
-                for x in a.d {
-                    stack.push(A::new(1, vec![x]));
+                let s = builtins::shape(&a);
+                stack.push(a);
+                stack.push(s);
+            }
+
+            Reshape => {
+                let n1 = stack.pop().ok_or(ErrorType::Eval)?;
+                let n2 = stack.pop().ok_or(ErrorType::Eval)?;
+                let r = builtins::reshape(n2, n1)?;
+                stack.push(r);
+            }
+
+            Ravel => {
+                let a = stack.pop().ok_or(ErrorType::Eval)?;
+                stack.push(builtins::ravel(a));
+            }
+
+            Transpose => {
+                let a = stack.pop().ok_or(ErrorType::Eval)?;
+                stack.push(builtins::transpose(a));
+            }
+
+            Permute => {
+                let n1 = stack.pop().ok_or(ErrorType::Eval)?;
+                let n2 = stack.pop().ok_or(ErrorType::Eval)?;
+                let r = builtins::permute(n2, n1)?;
+                stack.push(r);
+            }
+
+            Enclose => {
+                let a = stack.pop().ok_or(ErrorType::Eval)?;
+                stack.push(builtins::enclose(a));
+            }
+
+            Disclose => {
+                let a = stack.pop().ok_or(ErrorType::Eval)?;
+                let r = builtins::disclose(a)?;
+                stack.push(r);
+            }
+
+            Call => {
+                let a = stack.pop().ok_or(ErrorType::Eval)?;
+                let ast = builtins::quotation(a)?;
+                vm.run(&ast, stack)?;
+            }
+
+            Reduce => {
+                let op = stack.pop().ok_or(ErrorType::Eval)?;
+                let a = stack.pop().ok_or(ErrorType::Eval)?;
+                let r = reduce(&builtins::quotation(op)?, a, vm)?;
+                stack.push(r);
+            }
+
+            Scan => {
+                let op = stack.pop().ok_or(ErrorType::Eval)?;
+                let a = stack.pop().ok_or(ErrorType::Eval)?;
+                let r = scan(&builtins::quotation(op)?, a, vm)?;
+                stack.push(r);
+            }
+
+            Each => {
+                let op = stack.pop().ok_or(ErrorType::Eval)?;
+                let a = stack.pop().ok_or(ErrorType::Eval)?;
+                let r = each(&builtins::quotation(op)?, a, vm)?;
+                stack.push(r);
+            }
+
+            Rank => {
+                let k = stack.pop().ok_or(ErrorType::Eval)?;
+                let op = stack.pop().ok_or(ErrorType::Eval)?;
+                if stack.len() < k.d.len() {
+                    let (n, len) = (self.arity() + k.d.len(), self.arity() + stack.len());
+                    return Err(ErrorType::Underflow(self.name().to_string(), n, len).into());
                 }
+                let args = stack.split_off(stack.len() - k.d.len());
+                let r = rank(&builtins::quotation(op)?, args, &k, vm)?;
+                stack.push(r);
+            }
+
+            Outer => {
+                let op = stack.pop().ok_or(ErrorType::Eval)?;
+                let b = stack.pop().ok_or(ErrorType::Eval)?;
+                let a = stack.pop().ok_or(ErrorType::Eval)?;
+                let r = outer(&builtins::quotation(op)?, a, b, vm)?;
+                stack.push(r);
+            }
+
+            Inner => {
+                let g = stack.pop().ok_or(ErrorType::Eval)?;
+                let f = stack.pop().ok_or(ErrorType::Eval)?;
+                let b = stack.pop().ok_or(ErrorType::Eval)?;
+                let a = stack.pop().ok_or(ErrorType::Eval)?;
+                let (f, g) = (builtins::quotation(f)?, builtins::quotation(g)?);
+                let r = inner(&f, &g, a, b, vm)?;
+                stack.push(r);
+            }
+
+            Transmute => {
+                let a = stack.pop().ok_or(ErrorType::Eval)?;
+                stack.extend(a.cells());
             }
 
             Duplicate => {
@@ -143,10 +268,8 @@ impl Builtins {
             Pop => {
                 stack.pop().ok_or(ErrorType::Eval)?;
             }
-
-            Forward => {}
         }
-        Ok(ControlFlow::Continue)
+        Ok(())
     }
 }
 
@@ -154,49 +277,324 @@ pub fn eval(
     ast: &Ast,
     stack: &mut Vec<A>,
     words: &HashMap<String, Ast>,
+    natives: &HashMap<String, Native>,
     debug: bool,
-) -> Result<(), ErrorType> {
-    use Token::*;
+) -> Result<(), Error> {
+    let mut vm = Vm::new(words, natives);
+    let entry = vm.compile(ast);
+    let r = vm.exec(entry, stack);
 
-    let mut i = 0;
+    if debug {
+        println!("{:?}", stack);
+        println!("{:?}", words);
+    }
 
-    while i < ast.len() {
-        match &ast[i] {
-            Word(name) => {
-                if let Some(word) = words.get(name.as_str()) {
-                    let _ = eval(word, stack, words, debug);
-                }
-            }
-            Data(data) => stack.push(data.clone()),
-            Builtin(func) => match func.eval(stack)? {
-                ControlFlow::SkipToThen => {
-                    i += ast[i..]
-                        .iter()
-                        .position(|x| x == &Builtin(Builtins::Forward))
-                        .ok_or(ErrorType::Eval)?;
-                }
-                ControlFlow::Repeat(n) => {
-                    i += 1;
-                    if i < ast.len() {
-                        let f = &ast[i];
-                        for _ in 0..n {
-                            let _ = eval(&vec![f.clone()], stack, words, debug);
-                        }
-                    } else {
-                        return Err(ErrorType::Eval);
-                    }
-                }
-                ControlFlow::Continue => {}
-            },
-            Definition(_) => {}
+    r
+}
+
+/// The builtin a quotation consists of, if it's nothing else.
+fn single_builtin(op: &Ast) -> Option<&Builtins> {
+    match op.as_slice() {
+        [Node {
+            token: Token::Builtin(b),
+            ..
+        }] => Some(b),
+        _ => None,
+    }
+}
+
+/// Evaluate the quotation `op` on a stack of its own holding only `args`, and
+/// return the one value it leaves behind. A quotation of a single builtin
+/// skips straight to the builtin.
+fn apply(op: &Rc<Ast>, args: Vec<A>, vm: &mut Vm) -> Result<A, Error> {
+    let mut stack = args;
+    match single_builtin(op) {
+        Some(b) => b.eval(&mut stack, vm)?,
+        None => vm.run(op, &mut stack)?,
+    }
+
+    match (stack.pop(), stack.is_empty()) {
+        (Some(r), true) => Ok(r),
+        _ => Err(ErrorType::Msg("Operand must leave exactly one value.").into()),
+    }
+}
+
+/// Fold `op` over the cells along the leading axis of `a`, from the left.
+fn reduce(op: &Rc<Ast>, a: A, vm: &mut Vm) -> Result<A, Error> {
+    if let (Some(b), 1) = (single_builtin(op), a.rank()) {
+        if let Some(k) = builtins::kernel(b) {
+            let mut xs = a.d.iter();
+            let first = match xs.next() {
+                Some(first) => first.clone(),
+                None => return identity(op).ok_or_else(|| ErrorType::Msg("Couldn't reduce empty array.").into()),
+            };
+            return xs
+                .try_fold(first, |acc, x| k(&acc, x))
+                .map(A::scalar)
+                .ok_or_else(|| ErrorType::Msg("Couldn't reduce, not all values were numbers.").into());
         }
-        i += 1;
     }
 
-    if debug {
-        println!("{:?}", stack);
-        println!("{:?}", words);
+    let mut cells = a.cells().into_iter();
+    let first = match cells.next() {
+        Some(first) => first,
+        None => return identity(op).ok_or_else(|| ErrorType::Msg("Couldn't reduce empty array.").into()),
+    };
+
+    cells.try_fold(first, |acc, c| apply(op, vec![acc, c], vm))
+}
+
+/// Like `reduce`, but keep every intermediate result.
+fn scan(op: &Rc<Ast>, a: A, vm: &mut Vm) -> Result<A, Error> {
+    let mut results: Vec<A> = Vec::with_capacity(a.len());
+    for c in a.cells() {
+        let r = match results.last() {
+            Some(acc) => apply(op, vec![acc.clone(), c], vm)?,
+            None => c,
+        };
+        results.push(r);
     }
 
-    Ok(())
+    let frame = a.shape.first().copied().into_iter().collect();
+    Ok(A::from_cells(frame, results))
+}
+
+/// Apply `op` to every element of `a`, opening any boxes first.
+fn each(op: &Rc<Ast>, a: A, vm: &mut Vm) -> Result<A, Error> {
+    let results: Result<Vec<_>, _> =
+        a.d.into_iter()
+            .map(|x| apply(op, vec![x.open()], vm))
+            .collect();
+
+    Ok(A::from_cells(a.shape, results?))
+}
+
+/// Apply `op` to the cells of `args`, where `k` holds the rank of the cells to
+/// take from each argument. A negative rank counts down from the rank of the
+/// argument instead. The frames the cells are laid out in must agree the same
+/// way the shapes of arithmetic arguments do, and the results are laid out in
+/// the longest frame.
+fn rank(op: &Rc<Ast>, args: Vec<A>, k: &A, vm: &mut Vm) -> Result<A, Error> {
+    let ks: Option<Vec<_>> =
+        k.d.iter()
+            .map(|x| x.as_f64().filter(|x| x.fract() == 0.))
+            .collect();
+    let ks = ks.ok_or(ErrorType::Msg("Couldn't apply rank, ranks must be integers."))?;
+    if ks.is_empty() {
+        return Err(ErrorType::Msg("Couldn't apply rank, no ranks given.").into());
+    }
+
+    let split: Vec<_> = args
+        .iter()
+        .zip(ks)
+        .map(|(a, k)| {
+            let r = a.rank() as f64;
+            let k = if k < 0. { (r + k).max(0.) } else { k.min(r) };
+            a.rank_cells(k as usize)
+        })
+        .collect();
+
+    let frame = split
+        .iter()
+        .map(|(f, _)| f)
+        .max_by_key(|f| f.len())
+        .cloned()
+        .unwrap_or_default();
+    for (f, _) in &split {
+        if !frame.starts_with(f) {
+            return Err(ErrorType::Shape(f.clone(), frame).into());
+        }
+    }
+
+    let n: usize = frame.iter().product();
+    let mut results = Vec::with_capacity(n);
+    for i in 0..n {
+        let cells = split
+            .iter()
+            .map(|(_, cells)| cells[i / (n / cells.len().max(1))].clone())
+            .collect();
+        results.push(apply(op, cells, vm)?);
+    }
+
+    Ok(A::from_cells(frame, results))
+}
+
+/// Apply `op` between every element of `a` and every element of `b`, giving a
+/// table with the axes of `a` followed by the axes of `b`.
+fn outer(op: &Rc<Ast>, a: A, b: A, vm: &mut Vm) -> Result<A, Error> {
+    let mut shape = a.shape.clone();
+    shape.extend(&b.shape);
+
+    // The kernel doesn't open boxes, so anything it can't combine goes
+    // through the general path, which does.
+    if let Some(k) = single_builtin(op).and_then(builtins::kernel) {
+        let d: Option<Vec<_>> =
+            a.d.iter()
+                .flat_map(|x| b.d.iter().map(move |y| k(x, y)))
+                .collect();
+        if let Some(d) = d {
+            return Ok(A::new(shape, d));
+        }
+    }
+
+    let mut results = Vec::with_capacity(a.d.len() * b.d.len());
+    for x in &a.d {
+        for y in &b.d {
+            let args = vec![x.clone().open(), y.clone().open()];
+            results.push(apply(op, args, vm)?);
+        }
+    }
+
+    Ok(A::from_cells(shape, results))
+}
+
+/// Apply `g` between each vector along the last axis of `a` and each vector
+/// along the first axis of `b`, and reduce the result with `f`. With `+` and
+/// `*` this is matrix multiplication.
+fn inner(f: &Rc<Ast>, g: &Rc<Ast>, a: A, b: A, vm: &mut Vm) -> Result<A, Error> {
+    let (la, lb) = (a.shape.last().copied(), b.shape.first().copied());
+    if la.unwrap_or(1) != lb.unwrap_or(1) {
+        return Err(ErrorType::Shape(a.shape, b.shape).into());
+    }
+
+    // Move the first axis of `b` last, so both arguments split into vectors.
+    let mut axes: Vec<_> = (1..b.rank()).map(|x| x as f64).collect();
+    if b.rank() > 0 {
+        axes.push(0.);
+    }
+    let b = builtins::permute(b, A::from_nums(&axes))?;
+    let (fa, rows) = a.rank_cells(1);
+    let (fb, cols) = b.rank_cells(1);
+
+    let mut results = Vec::with_capacity(rows.len() * cols.len());
+    for row in &rows {
+        for col in &cols {
+            let z = apply(g, vec![row.clone(), col.clone()], vm)?;
+            results.push(reduce(f, z, vm)?);
+        }
+    }
+
+    let mut frame = fa;
+    frame.extend(fb);
+    Ok(A::from_cells(frame, results))
+}
+
+/// The value reducing an empty array gives for the arithmetic and boolean
+/// builtins.
+fn identity(op: &Ast) -> Option<A> {
+    use Builtins::*;
+
+    match single_builtin(op) {
+        Some(Plus | Minus | Or) => Some(A::from_num(0.)),
+        Some(Multiply | Divide | And) => Some(A::from_num(1.)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::array::Prim;
+    use crate::parser;
+
+    /// The stack left by running `src` on an empty one.
+    fn run(src: &str) -> Vec<A> {
+        try_run(src).unwrap()
+    }
+
+    fn try_run(src: &str) -> Result<Vec<A>, Error> {
+        let (ast, words) = parser::parse(src, "").unwrap();
+        let mut stack = vec![];
+        eval(&ast, &mut stack, &words, &HashMap::new(), false).map(|()| stack)
+    }
+
+    fn nums(shape: &[usize], xs: &[f64]) -> A {
+        A::new(shape.to_vec(), xs.iter().map(|x| Prim::Num(*x)).collect())
+    }
+
+    #[test]
+    fn reducing_an_empty_array_gives_the_identity() {
+        assert_eq!(run("[ ] { + } red"), vec![A::from_num(0.)]);
+        assert_eq!(run("[ ] { * } red"), vec![A::from_num(1.)]);
+        let e = try_run("[ ] { swp } red").unwrap_err();
+        assert!(matches!(e.ty, ErrorType::Msg("Couldn't reduce empty array.")));
+    }
+
+    #[test]
+    fn reduce_and_scan_fold_over_rows() {
+        assert_eq!(run("[ 1 2 3 ] { + } red"), vec![A::from_num(6.)]);
+        assert_eq!(run("[ [ 1 2 ] [ 3 4 ] ] { + } red"), vec![nums(&[2], &[4., 6.])]);
+        assert_eq!(run("[ [ 1 2 ] [ 3 4 ] ] { + 0 + } red"), vec![nums(&[2], &[4., 6.])]);
+        assert_eq!(run("[ 1 2 3 ] { + } scn"), vec![nums(&[3], &[1., 3., 6.])]);
+        let rows = run("[ [ 1 2 ] [ 3 4 ] ] { * } scn");
+        assert_eq!(rows, vec![nums(&[2, 2], &[1., 2., 3., 8.])]);
+    }
+
+    #[test]
+    fn each_boxes_ragged_results() {
+        assert_eq!(run("[ 1 2 3 ] { 2 * } ech"), vec![nums(&[3], &[2., 4., 6.])]);
+        let ragged = run("[ 1 2 3 ] { 0 swp rsh } ech");
+        assert_eq!(ragged[0].shape, vec![3]);
+        let boxed = A::scalar(Prim::Boxed(Box::new(nums(&[2], &[0., 0.]))));
+        assert_eq!(ragged[0].d[1], boxed.d[0]);
+        let opened = run("[ <[ 1 2 ]> <[ 3 4 ]> ] { 1 + } ech");
+        assert_eq!(opened, vec![nums(&[2, 2], &[2., 3., 4., 5.])]);
+    }
+
+    #[test]
+    fn operands_leave_one_value() {
+        for src in ["[ 1 2 ] { dup } ech", "[ 1 2 ] { pop } ech", "[ 1 2 3 ] { swp dup } red"] {
+            let e = try_run(src).unwrap_err();
+            assert!(matches!(e.ty, ErrorType::Msg("Operand must leave exactly one value.")), "`{}`", src);
+        }
+    }
+
+    #[test]
+    fn rank_applies_to_cells() {
+        let rows = run("[ [ 1 2 3 ] [ 4 5 6 ] ] { { + } red } 1 rnk");
+        assert_eq!(rows, vec![nums(&[2], &[6., 15.])]);
+        let added = run("[ [ 1 2 3 ] [ 4 5 6 ] ] [ 10 20 ] { + } [ 1 0 ] rnk");
+        assert_eq!(added, vec![nums(&[2, 3], &[11., 12., 13., 24., 25., 26.])]);
+        let negative = run("[ [ 1 2 3 ] [ 4 5 6 ] ] { { + } red } -1 rnk");
+        assert_eq!(negative, rows);
+    }
+
+    #[test]
+    fn rank_over_an_empty_frame() {
+        let sums = run("[ ] [ 0 3 ] rsh { { + } red } 1 rnk");
+        assert_eq!(sums, vec![nums(&[0], &[])]);
+    }
+
+    #[test]
+    fn outer_product_has_the_axes_of_both() {
+        let table = run("[ 1 2 3 ] [ 10 20 ] { + } out");
+        assert_eq!(table, vec![nums(&[3, 2], &[11., 21., 12., 22., 13., 23.])]);
+        let boxed = run("[ <[ 1 2 ]> <[ 3 4 ]> ] [ 10 ] { + } out");
+        assert_eq!(boxed, run("[ <[ 1 2 ]> <[ 3 4 ]> ] [ 10 ] { + 0 + } out"));
+        assert_eq!(boxed, vec![nums(&[2, 1, 2], &[11., 12., 13., 14.])]);
+        assert!(try_run("[ 1 ] \"a\" { + } out").is_err());
+        let empty = run("[ ] [ 1 2 ] { + } out");
+        assert_eq!(empty[0].shape, vec![0, 2]);
+    }
+
+    #[test]
+    fn inner_product_multiplies_matrices() {
+        let m = run("[ [ 1 2 ] [ 3 4 ] ] dup { + } { * } dot");
+        assert_eq!(m, vec![nums(&[2, 2], &[7., 10., 15., 22.])]);
+        assert_eq!(run("[ 1 2 ] [ 10 20 ] { + } { * } dot"), vec![A::from_num(50.)]);
+        let empty = run("[ ] [ 0 2 ] rsh [ ] [ 2 0 ] rsh { + } { * } dot");
+        assert_eq!(empty[0].shape, vec![0, 0]);
+    }
+
+    #[test]
+    fn underflow_names_the_word_and_its_arity() {
+        let (ast, words) = parser::parse("1 +", "").unwrap();
+        let e = eval(&ast, &mut vec![], &words, &HashMap::new(), false).unwrap_err();
+        assert!(matches!(&e.ty, ErrorType::Underflow(w, 2, 1) if w == "+"));
+        assert_eq!(e.ty.to_string(), "`+` needs 2 values, stack has 1.");
+        assert_eq!(e.location.unwrap().token, "+");
+
+        let e = eval(&parser::parse("pop", "").unwrap().0, &mut vec![], &words, &HashMap::new(), false);
+        assert_eq!(e.unwrap_err().ty.to_string(), "`pop` needs 1 value, stack has 0.");
+    }
 }
@@ -0,0 +1,72 @@
+use std::collections::HashMap;
+
+use crossterm::style::{style, Color};
+use nd::parser::{self, Ast};
+
+/// Colour `line` for the terminal by what each part of it is: builtins, the
+/// words in `words`, numbers, strings, comments and stack effect annotations.
+/// Unlike the lexer this never fails, so that lines can be coloured while
+/// they're being typed.
+pub fn highlight(line: &str, words: &HashMap<String, Ast>) -> String {
+    let chars: Vec<char> = line.chars().collect();
+    let mut out = String::new();
+    // 1 right after a `:` and 2 right after the name following it, which is
+    // the only place a stack effect annotation can start.
+    let mut head = 0;
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() || "[]{}:;".contains(c) {
+            if !c.is_whitespace() {
+                head = if c == ':' { 1 } else { 0 };
+            }
+            out.push(c);
+            i += 1;
+            continue;
+        }
+        let annotation = head == 2 && c == '(';
+        head = if head == 1 { 2 } else { 0 };
+
+        // Comments, annotations and strings run to their end, or that of the
+        // line if it isn't there yet.
+        let closing = |close: char| {
+            chars[i + 1..]
+                .iter()
+                .position(|&cm| cm == close)
+                .map_or(chars.len(), |p| i + p + 2)
+        };
+        let (end, color) = match c {
+            '#' => (chars.len(), Some(Color::DarkGrey)),
+            '(' if annotation => (closing(')'), Some(Color::DarkGrey)),
+            '"' => (closing('"'), Some(Color::Magenta)),
+            _ => {
+                let end = chars[i..]
+                    .iter()
+                    .position(|cm| cm.is_whitespace() || "[]{};".contains(*cm))
+                    .map_or(chars.len(), |p| i + p);
+                let token: String = chars[i..end].iter().collect();
+                (end, word_color(&token, words))
+            }
+        };
+
+        let part: String = chars[i..end].iter().collect();
+        match color {
+            Some(color) => out.push_str(&style(part).with(color).to_string()),
+            None => out.push_str(&part),
+        }
+        i = end;
+    }
+    out
+}
+
+fn word_color(token: &str, words: &HashMap<String, Ast>) -> Option<Color> {
+    if parser::keywords().any(|k| k == token) {
+        Some(Color::Blue)
+    } else if words.contains_key(token) {
+        Some(Color::Green)
+    } else if token.parse::<f64>().is_ok() {
+        Some(Color::Yellow)
+    } else {
+        None
+    }
+}
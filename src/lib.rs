@@ -0,0 +1,224 @@
+//! nd, a stack based array language. The `Interpreter` evaluates nd source
+//! and keeps the stack and the defined words between evaluations, so it can
+//! be embedded in other programs as well as run by the `nd` binary.
+
+use std::collections::HashMap;
+
+pub mod array;
+mod builtins;
+pub mod check;
+pub mod errors;
+mod eval;
+pub mod parser;
+mod session;
+mod vm;
+
+use array::A;
+use errors::Error;
+use parser::{Ast, Effect, Token};
+
+pub use eval::Native;
+
+/// The state of an nd program: the stack, the words defined so far and the
+/// source they were read from.
+#[derive(Default)]
+pub struct Interpreter {
+    stack: Vec<A>,
+    words: HashMap<String, Ast>,
+    /// The stack effects declared for words.
+    effects: HashMap<String, Effect>,
+    natives: HashMap<String, Native>,
+    /// The source of everything evaluated, by name, for rendering errors.
+    sources: HashMap<String, String>,
+    /// The number of sources given to `eval`, to name each one differently.
+    evals: usize,
+    /// Print the stack and words after every evaluation.
+    pub debug: bool,
+}
+
+impl Interpreter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Evaluate `source`, leaving its results on the stack and keeping any
+    /// words it defines. Each source is named `<eval:N>` in errors, so words
+    /// defined by earlier ones still point into their own source.
+    pub fn eval(&mut self, source: &str) -> Result<(), Error> {
+        self.evals += 1;
+        let name = format!("<eval:{}>", self.evals);
+        self.eval_named(&name, source)
+    }
+
+    /// Like `eval`, with `name` as the file the source came from in errors.
+    pub fn eval_named(&mut self, name: &str, source: &str) -> Result<(), Error> {
+        self.sources.insert(name.to_string(), source.to_string());
+        let (ast, words) = parser::parse(source, name)?;
+        self.define(&ast, words);
+        eval::eval(&ast, &mut self.stack, &self.words, &self.natives, self.debug)
+    }
+
+    /// Check the stack effects of `source` without evaluating it, as if it
+    /// was about to run on the current stack, returning the problems found.
+    /// See `check::check`.
+    pub fn check(&mut self, name: &str, source: &str) -> Result<Vec<Error>, Error> {
+        self.sources.insert(name.to_string(), source.to_string());
+        let (ast, words) = parser::parse(source, name)?;
+        let mut all = self.words.clone();
+        all.extend(words);
+        Ok(check::check(&ast, &all, &self.natives, self.stack.len()))
+    }
+
+    /// The words defined and the values on the stack, written so that
+    /// `restore` can read them back. The words are written as source.
+    pub fn save(&self) -> String {
+        session::write(&self.stack, &self.words, &self.effects)
+    }
+
+    /// Replace the stack and the words with those in `session`, as written
+    /// by `save`, naming `name` as its file in errors. Nothing is replaced if
+    /// it can't be read.
+    pub fn restore(&mut self, name: &str, session: &str) -> Result<(), Error> {
+        self.sources.insert(name.to_string(), session.to_string());
+        let session = session::read(session, name)?;
+        self.words.clear();
+        self.effects.clear();
+        self.define(&session.ast, session.words);
+        self.stack = session.stack;
+        Ok(())
+    }
+
+    /// Keep the definitions in `ast`, which were parsed into `words`.
+    fn define(&mut self, ast: &Ast, words: HashMap<String, Ast>) {
+        for node in ast {
+            if let Token::Definition((name, _, effect)) = &node.token {
+                match effect {
+                    Some(effect) => self.effects.insert(name.clone(), effect.clone()),
+                    None => self.effects.remove(name),
+                };
+            }
+        }
+        self.words.extend(words);
+    }
+
+    /// Render `err` with the source it happened in.
+    pub fn render(&self, err: &Error) -> String {
+        err.render(&self.sources)
+    }
+
+    /// Render `err` as a warning, with the source it happened in.
+    pub fn render_warning(&self, err: &Error) -> String {
+        err.render_warning(&self.sources)
+    }
+
+    /// Define the word `name` as the Rust function `f`, which takes `ins`
+    /// values from the stack, deepest first, and returns the `outs` values to
+    /// leave in their place. Words defined in nd take precedence over it.
+    ///
+    /// ```
+    /// let mut nd = nd::Interpreter::new();
+    /// nd.register("km", 1, 1, |args| {
+    ///     let miles = args[0].d()[0].as_f64().ok_or("Expected a number.")?;
+    ///     Ok(vec![nd::array::A::from_num(miles * 1.609344)])
+    /// });
+    /// nd.eval("10 km").unwrap();
+    /// ```
+    pub fn register<F>(&mut self, name: &str, ins: usize, outs: usize, f: F)
+    where
+        F: Fn(Vec<A>) -> Result<Vec<A>, String> + 'static,
+    {
+        self.natives.insert(name.to_string(), Native::new(ins, outs, f));
+    }
+
+    pub fn push(&mut self, a: A) {
+        self.stack.push(a);
+    }
+
+    pub fn pop(&mut self) -> Option<A> {
+        self.stack.pop()
+    }
+
+    /// The values on the stack, from the bottom up.
+    pub fn stack(&self) -> &[A] {
+        &self.stack
+    }
+
+    /// The words defined so far, by name.
+    pub fn words(&self) -> &HashMap<String, Ast> {
+        &self.words
+    }
+
+    /// The code of the word `name`, if it's defined.
+    pub fn word(&self, name: &str) -> Option<&Ast> {
+        self.words.get(name)
+    }
+
+    /// The stack effect declared for the word `name`, if any.
+    pub fn effect(&self, name: &str) -> Option<&Effect> {
+        self.effects.get(name)
+    }
+
+    /// Remove the definition of the word `name`, returning whether it was
+    /// defined. Words calling it fail when they get to the call.
+    pub fn forget(&mut self, name: &str) -> bool {
+        self.effects.remove(name);
+        self.words.remove(name).is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::errors::ErrorType;
+
+    #[test]
+    fn errors_point_into_the_source_of_each_eval() {
+        let mut nd = Interpreter::new();
+        nd.eval(": f\n  1 +\n  foo ;").unwrap();
+        let e = nd.eval("3 f").unwrap_err();
+        let rendered = nd.render(&e);
+        assert!(rendered.contains(" --> <eval:1>:3:3\n"), "{}", rendered);
+        assert!(rendered.contains("3 |   foo ;\n"), "{}", rendered);
+        assert!(rendered.contains("= in `f` at <eval:2>:1:3"), "{}", rendered);
+    }
+
+    /// An interpreter with `km`, which converts miles, and `pair`, which
+    /// wrongly leaves one value instead of two.
+    fn with_natives() -> Interpreter {
+        let mut nd = Interpreter::new();
+        nd.register("km", 1, 1, |args| {
+            let miles = args[0].d()[0].as_f64().ok_or("Expected a number.")?;
+            Ok(vec![A::from_num(miles * 1.609344)])
+        });
+        nd.register("pair", 1, 2, Ok);
+        nd
+    }
+
+    #[test]
+    fn natives_leave_their_results() {
+        let mut nd = with_natives();
+        nd.eval("7 10 km").unwrap();
+        assert_eq!(nd.stack(), &[A::from_num(7.), A::from_num(16.09344)]);
+
+        let e = nd.eval("\"a\" km").unwrap_err();
+        assert!(matches!(&e.ty, ErrorType::Native(m) if m == "Expected a number."));
+    }
+
+    #[test]
+    fn natives_check_the_stack() {
+        let mut nd = with_natives();
+        let e = nd.eval("km").unwrap_err();
+        assert!(matches!(&e.ty, ErrorType::Underflow(w, 1, 0) if w == "km"));
+        assert_eq!(e.location.unwrap().token, "km");
+
+        let e = nd.eval("1 pair").unwrap_err();
+        assert!(matches!(e.ty, ErrorType::Msg("Native word left the wrong number of values.")));
+    }
+
+    #[test]
+    fn words_shadow_natives() {
+        let mut nd = with_natives();
+        nd.eval(": km 2 * ; 10 km").unwrap();
+        assert_eq!(nd.stack(), &[A::from_num(20.)]);
+    }
+}
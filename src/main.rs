@@ -1,187 +1,423 @@
 use std::collections::HashMap;
 use std::env;
 use std::fs;
-use std::io::{self, Write};
+use std::io::{self, IsTerminal, Read, Write};
+use std::process;
 
-mod builtins;
-mod array;
-mod errors;
-mod eval;
-mod parser;
+use nd::array::A;
+use nd::errors::{Error, ErrorType};
+use nd::parser::{self, Ast, Token};
+use nd::Interpreter;
 
-use array::A;
-use eval::eval;
-use parser::Ast;
+use crossterm::style::{style, Color};
+use crossterm::Result;
 
-use crossterm::{
-    cursor,
-    event::{self, Event, KeyCode, KeyEvent},
-    execute, terminal, Result,
-};
+mod editor;
+mod highlight;
+
+use editor::{Editor, Input, Line};
+
+/// How many characters of each value the status line shows.
+const PREVIEW_LEN: usize = 24;
 
 fn main() -> Result<()> {
     let mut buffer = String::new();
-
-    let mut stack: Vec<A> = Vec::new();
-    let mut words = HashMap::new();
+    let mut interpreter = Interpreter::new();
 
     let mut args = env::args();
-    if let Some(f) = args.nth(1) {
-        buffer = fs::read_to_string(f).expect("Invalid file name.");
-        for line in buffer.lines() {
-            run(&line, &mut stack, &mut words, false);
+    let ok = match args.nth(1).as_deref() {
+        Some("--check") => {
+            let f = args.next().expect("Missing file name.");
+            buffer = fs::read_to_string(&f).expect("Invalid file name.");
+            let errors = interpreter.check(&f, &buffer).unwrap_or_else(|err| vec![err]);
+            for err in &errors {
+                eprintln!("{}", interpreter.render(err));
+            }
+            errors.is_empty()
         }
-    } else {
-        repl(&mut buffer, &mut stack, &mut words)?;
+        Some("-e") => {
+            let code = args.next().expect("Missing code to evaluate.");
+            run(&mut interpreter, "<-e>", &code)
+        }
+        Some(f) => {
+            buffer = fs::read_to_string(f).expect("Invalid file name.");
+            run(&mut interpreter, f, &buffer)
+        }
+        // Without a terminal to type into, stdin holds a script.
+        None if !io::stdin().is_terminal() => {
+            io::stdin().read_to_string(&mut buffer)?;
+            run(&mut interpreter, "<stdin>", &buffer)
+        }
+        None => {
+            repl(&mut interpreter)?;
+            true
+        }
+    };
+
+    if !ok {
+        process::exit(1);
     }
     Ok(())
 }
 
-fn repl(buffer: &mut String, stack: &mut Vec<A>, words: &mut HashMap<String, Ast>) -> Result<()> {
-    let mut debugging = false;
+fn repl(interpreter: &mut Interpreter) -> Result<()> {
+    let mut editor = Editor::new();
+    let mut buffer = String::new();
+    let mut entries = 0;
+    let mut status = true;
 
     loop {
-        match event::read()? {
-            Event::Key(k) => match k {
-                KeyEvent {
-                    code: KeyCode::Char(c),
-                    ..
-                } => {
-                    print!("{}", c);
-                    buffer.push(c);
-                    io::stdout().flush().unwrap();
-                }
-                KeyEvent {
-                    code: KeyCode::Enter,
-                    ..
-                } => {
-                    if buffer.trim() == ".debug" {
-                        debugging = !debugging;
+        let words = interpreter.words();
+        let line = editor.read_line(
+            "",
+            &mut |line| complete(line, words),
+            &|line| highlight::highlight(line, words),
+        )?;
+        let line = match line {
+            Input::Line(line) => line,
+            // The way out of input that can't be completed.
+            Input::Cancel => {
+                buffer.clear();
+                continue;
+            }
+            Input::End => break,
+        };
+        editor.add_history(&line);
+        buffer.push_str(&line);
+
+        // Commands are matched on their first word, the rest is their
+        // argument. Only code and the commands running it can change the
+        // stack, so it's only shown after those.
+        let command = buffer.trim();
+        let (name, arg) = command.split_once(char::is_whitespace).unwrap_or((command, ""));
+        let arg = arg.trim();
+        let changed = match name {
+            ".quit" => break,
+            ".debug" => {
+                interpreter.debug = !interpreter.debug;
+                false
+            }
+            ".status" => {
+                status = !status;
+                false
+            }
+            ".words" => {
+                print_words(interpreter);
+                false
+            }
+            ".stack" => {
+                print_stack(interpreter.stack());
+                false
+            }
+            ".see" => {
+                match interpreter.word(arg) {
+                    Some(ast) => {
+                        let effect = interpreter.effect(arg).cloned();
+                        let definition = Token::Definition((arg.to_string(), ast.clone(), effect));
+                        println!("{}", definition.pretty());
                     }
-                    if let Some(i) = buffer.char_indices().map(|(i, _)| i).nth(5) {
-                        if buffer.len() > 5 && &buffer[..i] == ".load" {
-                            let file = fs::read_to_string(&buffer[i..].trim())
-                                .expect("Invalid file name.");
-                            println!("");
-                            for line in file.lines() {
-                                run(&line, stack, words, debugging);
-                            }
+                    None => eprintln!("{}", interpreter.render(&undefined(arg))),
+                }
+                false
+            }
+            ".forget" => {
+                if !interpreter.forget(arg) {
+                    eprintln!("{}", interpreter.render(&undefined(arg)));
+                }
+                false
+            }
+            ".save" => {
+                if let Err(err) = fs::write(arg, interpreter.save()) {
+                    eprintln!("Couldn't save to {}: {}", arg, err);
+                }
+                false
+            }
+            ".restore" => {
+                match fs::read_to_string(arg) {
+                    Ok(session) => {
+                        if let Err(err) = interpreter.restore(arg, &session) {
+                            eprintln!("{}", interpreter.render(&err));
                         }
                     }
-
-                    if buffer.trim() == ".quit" {
-                        break;
-                    }
-
-                    println!("");
-                    run(&buffer, stack, words, debugging);
-                    io::stdout().flush().unwrap();
-
-                    buffer.clear();
+                    Err(err) => eprintln!("Couldn't restore from {}: {}", arg, err),
                 }
-                KeyEvent {
-                    code: KeyCode::Tab, ..
-                } => {
-                    let l = buffer.len();
-                    if l >= 1 {
-                        match &buffer[l - 1..] {
-                            "?" => {
-                                print_help();
-                                buffer.clear();
-                            }
-                            _ => {}
+                true
+            }
+            ".load" => {
+                match fs::read_to_string(arg) {
+                    Ok(file) => {
+                        // Parse errors are left for evaluating the file to report.
+                        for err in interpreter.check(arg, &file).unwrap_or_default() {
+                            println!("{}", interpreter.render_warning(&err));
                         }
+                        run(interpreter, arg, &file);
                     }
+                    Err(err) => eprintln!("Couldn't load {}: {}", arg, err),
                 }
-                KeyEvent {
-                    code: KeyCode::Backspace,
-                    ..
-                } => print_char_in_place(buffer, None),
-                _ => {}
-            },
-            _ => {}
+                true
+            }
+            // Words can start with `.` too, so only names that aren't words
+            // are taken to be mistyped commands.
+            _ if name.starts_with('.') && interpreter.word(name).is_none() => {
+                eprintln!("Unknown command `{}`.", name);
+                false
+            }
+            _ if parser::is_incomplete(&buffer) => {
+                // Keep reading until the input forms a whole program.
+                buffer.push('\n');
+                continue;
+            }
+            _ => {
+                entries += 1;
+                let name = format!("<repl:{}>", entries);
+                run(interpreter, &name, &buffer);
+                true
+            }
+        };
+        if status && changed {
+            print_status(interpreter.stack());
         }
+        io::stdout().flush().unwrap();
+
+        buffer.clear();
     }
     Ok(())
 }
 
+/// Show the help when the line ends in `?`, and otherwise complete the word
+/// before the cursor from the keywords and the defined words, listing them if
+/// there is more than one. Returns whether anything was printed.
+fn complete(line: &mut Line, words: &HashMap<String, Ast>) -> bool {
+    if line.chars.last() == Some(&'?') {
+        println!();
+        print_help();
+        line.set("");
+        return true;
+    }
+
+    let start = line.token_start();
+    let prefix: String = line.chars[start..line.cursor].iter().collect();
+    if prefix.is_empty() {
+        return false;
+    }
+
+    let mut candidates: Vec<&str> = parser::keywords().collect();
+    candidates.extend(words.keys().map(String::as_str));
+    candidates.retain(|w| w.starts_with(&prefix));
+    candidates.sort_unstable();
+    candidates.dedup();
+
+    match candidates.as_slice() {
+        [] => false,
+        [word] => {
+            line.replace(start, &format!("{} ", word));
+            false
+        }
+        [_, _, ..] => {
+            let common = common_prefix(&candidates);
+            if common.len() > prefix.len() {
+                line.replace(start, common);
+                return false;
+            }
+            println!();
+            println!("{}", candidates.join("  "));
+            true
+        }
+    }
+}
+
+/// The longest prefix all of `words` start with.
+fn common_prefix<'a>(words: &[&'a str]) -> &'a str {
+    let first = match words.first() {
+        Some(first) => *first,
+        None => return "",
+    };
+    let end = words[1..].iter().fold(first.len(), |end, w| {
+        first[..end]
+            .char_indices()
+            .zip(w.chars())
+            .find(|((_, a), b)| a != b)
+            .map_or(end.min(w.len()), |((i, _), _)| i)
+    });
+    &first[..end]
+}
+
+/// Show the values on the stack on one line, bottom first, each with its
+/// shape and the start of its contents.
+fn print_status(stack: &[A]) {
+    let values: Vec<String> = stack
+        .iter()
+        .map(|a| {
+            let text = a.to_string().split_whitespace().collect::<Vec<_>>().join(" ");
+            let preview = match text.char_indices().nth(PREVIEW_LEN) {
+                Some((i, _)) => format!("{}…", &text[..i]),
+                None => text,
+            };
+            match a.rank() {
+                0 => preview,
+                _ => format!("{} {}", shape(a), preview),
+            }
+        })
+        .collect();
+    let line = if values.is_empty() {
+        "stack empty".to_string()
+    } else {
+        format!("stack: {}", values.join(" │ "))
+    };
+    println!("{}", style(line).with(Color::DarkGrey));
+}
+
+/// Show the values on the stack one after another, bottom first, numbered
+/// from the bottom and with the shape of those that aren't scalars.
+fn print_stack(stack: &[A]) {
+    if stack.is_empty() {
+        println!("The stack is empty.");
+    }
+    for (i, a) in stack.iter().enumerate() {
+        let label = format!("{}: ", i);
+        // Line the rows of higher rank arrays up under the first.
+        let text = a.to_string().replace('\n', &format!("\n{:1$}", "", label.len()));
+        match a.rank() {
+            0 => println!("{}{}", label, text),
+            _ => println!("{}{}  {}", label, text, style(shape(a)).with(Color::DarkGrey)),
+        }
+    }
+}
+
+/// List the defined words in order, with their declared stack effects.
+fn print_words(interpreter: &Interpreter) {
+    let mut names: Vec<&String> = interpreter.words().keys().collect();
+    names.sort();
+    if names.is_empty() {
+        println!("No words are defined.");
+    }
+    for name in names {
+        match interpreter.effect(name) {
+            Some(effect) => println!("{} {}", name, effect),
+            None => println!("{}", name),
+        }
+    }
+}
+
+/// The shape of `a` written like `2×3`.
+fn shape(a: &A) -> String {
+    let axes: Vec<String> = a.shape().iter().map(|n| n.to_string()).collect();
+    axes.join("×")
+}
+
+fn undefined(name: &str) -> Error {
+    ErrorType::Undefined(name.to_string()).into()
+}
+
 fn print_help() {
     println!("Builtin functions");
     println!("=================");
-    println!("");
+    println!();
     println!("IO");
     println!("--");
-    println!("");
+    println!();
     println!("_ Print the top stack value.");
-    println!("");
+    println!();
     println!("Control flow");
     println!("------------");
-    println!("");
-    println!("if Conditionally evaluate the next code based on the truthiness of the top stack element. If falsy, continue from next then.");
+    println!();
+    println!("if Conditionally evaluate the following code based on the truthiness of the top stack element. If falsy, continue from the matching else, or after the matching then.");
+    println!("else Marker to identify the start of the falsy branch.");
     println!("then Marker to identify branch end.");
-    println!("do Repeat the following word n times, where n is the value of the top stack element.");
-    println!("");
+    println!("do Repeat the code up to the matching loop n times, where n is the value of the top stack element.");
+    println!("loop Marker to identify the end of a do loop.");
+    println!("i Push the index of the innermost do loop.");
+    println!("begin Start a while or until loop.");
+    println!("while Stop the loop if the top stack element is falsy, otherwise evaluate the code up to the matching repeat and start over.");
+    println!("repeat Marker to identify the end of a while loop.");
+    println!("until Repeat the code since the matching begin until the top stack element is truthy.");
+    println!("call Evaluate the quotation on top of the stack.");
+    println!();
     println!("Equality");
     println!("--------");
-    println!("");
+    println!();
     println!("eql Test value-wise equality between top two stack elements.");
-    println!("");
+    println!();
     println!("Arithmetic");
     println!("----------");
-    println!("");
+    println!();
     println!("+ Value-wise addition between top two stack elements.");
     println!("- Value-wise subtraction between top two stack elements.");
     println!("* Value-wise multiplication between top two stack elements.");
     println!("/ Value-wise division between top two stack elements.");
-    println!("");
-    println!("All arithmetic operators repeat the top stack element, so [ 1 2 3 ] 1 + evaluates to [ 2 3 4 ].");
-    println!("");
+    println!();
+    println!("All arithmetic operators extend scalars and arrays whose shape is the leading part of the other argument's, so [ 1 2 3 ] 1 + evaluates to [ 2 3 4 ].");
+    println!();
     println!("Array manipulation");
     println!("------------------");
-    println!("");
+    println!();
     println!("cat Concatenate top stack element to the following stack element.");
     println!("trm Transmute the top stack element into individual elements.");
     println!("len Push the length of the top stack element onto the stack.");
-    println!("");
+    println!("shp Push the shape of the top stack element onto the stack.");
+    println!("rsh Reshape the second stack element into the shape given by the top stack element.");
+    println!("rav Ravel the top stack element into a vector of all its values.");
+    println!("tsp Transpose the top stack element by reversing the order of its axes.");
+    println!("prm Permute the axes of the second stack element by the axes given in the top stack element.");
+    println!("enc Enclose the top stack element in a box. Boxes are shown, and can be written, as <[ 1 2 3 ]>.");
+    println!("dsc Disclose the boxes of the top stack element.");
+    println!();
+    println!("Adverbs");
+    println!("-------");
+    println!();
+    println!("red Reduce the second stack element along its leading axis with the quotation on top of the stack.");
+    println!("scn Scan the second stack element along its leading axis with the quotation on top of the stack.");
+    println!("ech Apply the quotation on top of the stack to each value of the second stack element.");
+    println!("out Apply the quotation on top of the stack between every pair of values from the two elements below it.");
+    println!("dot Inner product of the two elements below the top two quotations, so {{ + }} {{ * }} dot is matrix multiplication.");
+    println!("rnk Apply the quotation below the top stack element to cells of the elements below it, with one cell rank per argument given in the top stack element.");
+    println!();
     println!("Stack manipulation");
     println!("------------------");
-    println!("");
+    println!();
     println!("dup Duplicate top stack element.");
     println!("pop Pop top stack element.");
     println!("swp Swap the top two stack elements.");
     println!("rot Move the bottom stack element to the top.");
     println!("clr Clear the stack.");
     println!("clr1 Clear all but the top stack element.");
-    println!("");
+    println!();
     println!("Definitions");
     println!("-----------");
-    println!("");
+    println!();
     println!(": Start word definition.");
     println!("; End word definition.");
     println!("[ Start element definition. If element only contains a single value, the brackets can be omitted.");
     println!("] End element definition.");
+    println!("{{ Start quotation, pushing the code up to the matching }} onto the stack unevaluated.");
+    println!("}} End quotation.");
+    println!("( Right after the name of a definition, declare its stack effect up to the next ), like : sq ( a -- b ) dup * ;");
 }
 
-fn print_char_in_place(buffer: &mut String, c: Option<char>) {
-    let _ = buffer.pop();
-    let _ = execute!(
-        io::stdout(),
-        cursor::MoveLeft(1),
-        terminal::Clear(terminal::ClearType::UntilNewLine)
-    );
-    if let Some(s) = c {
-        buffer.push(s);
-        print!("{}", s);
+/// Evaluate `buffer`, which is read from `file`, and print any error,
+/// returning whether there was none.
+fn run(interpreter: &mut Interpreter, file: &str, buffer: &str) -> bool {
+    match interpreter.eval_named(file, buffer) {
+        Ok(()) => true,
+        Err(err) => {
+            eprintln!("{}", interpreter.render(&err));
+            false
+        }
     }
-    io::stdout().flush().unwrap();
 }
 
-fn run(buffer: &str, stack: &mut Vec<A>, words: &mut HashMap<String, Ast>, debugging: bool) {
-    match parser::parse(buffer) {
-        Ok((tokens, w)) => {
-            words.extend(w);
-            let _ = eval(&tokens, stack, words, debugging);
-        }
-        Err(err) => println!("Couldn't parse line: {:?}", err),
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn common_prefix_ends_between_characters() {
+        assert_eq!(common_prefix(&["xé", "xè"]), "x");
+        assert_eq!(common_prefix(&["éa", "éb", "éab"]), "é");
+        assert_eq!(common_prefix(&["red", "rev", "rsh"]), "r");
+        assert_eq!(common_prefix(&["scan", "sc"]), "sc");
+        assert_eq!(common_prefix(&["dup", "dup"]), "dup");
+        assert_eq!(common_prefix(&["ab", "cd"]), "");
+        assert_eq!(common_prefix(&[]), "");
     }
 }
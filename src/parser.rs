@@ -1,18 +1,30 @@
 use std::collections::HashMap;
-use std::error::Error;
+use std::fmt;
+use std::iter::Peekable;
+use std::rc::Rc;
+use std::str::Chars;
 
-use crate::array::A;
-use crate::errors::ErrorType;
+use crate::array::{Prim, A};
+use crate::errors::{Error, ErrorType, Span};
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 enum LexemeType {
     Number,
     Str,
     Array,
+    Boxed,
+    Quotation,
     Word,
     Print,
     If,
+    Else,
     Do,
+    Loop,
+    Begin,
+    While,
+    Repeat,
+    Until,
+    Index,
     And,
     Or,
     Forward,
@@ -23,6 +35,20 @@ enum LexemeType {
     Divide,
     Concat,
     Len,
+    Shape,
+    Reshape,
+    Ravel,
+    Transpose,
+    Permute,
+    Enclose,
+    Disclose,
+    Call,
+    Reduce,
+    Scan,
+    Each,
+    Rank,
+    Outer,
+    Inner,
     Transmute,
     Duplicate,
     Swap,
@@ -37,32 +63,191 @@ enum LexemeType {
 struct Lexeme {
     string: String,
     ty: LexemeType,
+    span: Span,
 }
 
 impl Lexeme {
-    fn new(st: &str, ty: LexemeType) -> Self {
+    fn new(st: &str, ty: LexemeType, span: Span) -> Self {
         Self {
             string: st.to_string(),
             ty,
+            span,
+        }
+    }
+
+    fn error(&self, ty: ErrorType) -> Error {
+        Error::new(ty, &self.span, &self.string)
+    }
+
+    fn node(&self, token: Token) -> Node {
+        Node {
+            token,
+            span: self.span.clone(),
+        }
+    }
+
+    /// Where the contents of a bracketed lexeme start.
+    fn inner(&self) -> Span {
+        Span {
+            col: self.span.col + 1,
+            ..self.span.clone()
         }
     }
 }
 
-pub type Ast = Vec<Token>;
+/// A token along with where it was written.
+#[derive(Debug, Clone)]
+pub struct Node {
+    pub token: Token,
+    pub span: Span,
+}
 
-fn parse_ast(lexemes: &[Lexeme]) -> Result<Ast, Box<dyn Error>> {
-    let tokens: Result<Vec<_>, _> = lexemes.iter().map(|l| Token::parse(l)).collect();
-    Ok(tokens?)
+/// Nodes are equal if their tokens are, wherever they were written.
+impl PartialEq for Node {
+    fn eq(&self, other: &Self) -> bool {
+        self.token == other.token
+    }
 }
 
-fn resolve_words(tokens: &[Token]) -> HashMap<String, Ast> {
-    let mut words = HashMap::new();
-    for token in tokens {
-        match token {
-            Token::Definition((name, ast)) => {
-                words.insert(name.to_string(), ast.clone());
+impl Node {
+    pub fn error(&self, ty: ErrorType) -> Error {
+        Error::new(ty, &self.span, &self.token.to_string())
+    }
+}
+
+impl fmt::Display for Node {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.token)
+    }
+}
+
+pub type Ast = Vec<Node>;
+
+fn parse_ast(lexemes: &[Lexeme]) -> Result<Ast, Error> {
+    let mut ls = lexemes.iter();
+    match parse_block(&mut ls)? {
+        (ast, None) => Ok(ast),
+        (_, Some(end)) => Err(end.error(ErrorType::Parse)),
+    }
+}
+
+/// A stack effect annotation, written `( a b -- c )` after the name of a
+/// definition, naming the values it takes and the values it leaves.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Effect {
+    pub ins: Vec<String>,
+    pub outs: Vec<String>,
+}
+
+impl Effect {
+    /// Read the annotation at the start of the body of a definition, if it
+    /// has one, along with the rest of the body.
+    fn parse(body: &str) -> Result<(Option<Self>, &str), ErrorType> {
+        let inner = match body.trim_start().strip_prefix('(') {
+            Some(inner) => inner,
+            None => return Ok((None, body)),
+        };
+
+        let end = inner.find(')').ok_or(ErrorType::Msg("Stack effect is missing `)`."))?;
+        let names: Vec<_> = inner[..end].split_whitespace().map(String::from).collect();
+        let split = names.iter().position(|n| n == "--");
+        let split = split.ok_or(ErrorType::Msg("Stack effect is missing `--`."))?;
+
+        let effect = Self {
+            outs: names[split + 1..].to_vec(),
+            ins: names[..split].to_vec(),
+        };
+        Ok((Some(effect), &inner[end + 1..]))
+    }
+}
+
+impl fmt::Display for Effect {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "( ")?;
+        for name in &self.ins {
+            write!(f, "{} ", name)?;
+        }
+        write!(f, "--")?;
+        for name in &self.outs {
+            write!(f, " {}", name)?;
+        }
+        write!(f, " )")
+    }
+}
+
+/// Parse the code inside a quotation or definition starting at `start`, which
+/// has to be complete on its own.
+fn parse_nested(buf: &str, start: Span) -> Result<Ast, Error> {
+    parse_ast(&lex(buf, start)?).map_err(|mut e| {
+        if let ErrorType::Incomplete = e.ty {
+            e.ty = ErrorType::Parse;
+        }
+        e
+    })
+}
+
+/// The error for the block opened by `open` that ended with `end` instead of
+/// its closing lexeme. Running out of input means the block may still be
+/// closed by more input.
+fn unclosed(open: &Lexeme, end: Option<&Lexeme>) -> Error {
+    match end {
+        Some(end) => end.error(ErrorType::Parse),
+        None => open.error(ErrorType::Incomplete),
+    }
+}
+
+/// Parse lexemes until the input runs out or a lexeme ending a block is
+/// reached, returning the ending lexeme along with the block.
+fn parse_block<'a, I>(ls: &mut I) -> Result<(Ast, Option<&'a Lexeme>), Error>
+where
+    I: Iterator<Item = &'a Lexeme>,
+{
+    let mut ast = vec![];
+    while let Some(l) = ls.next() {
+        match l.ty {
+            LexemeType::If => {
+                let (t, end) = parse_block(ls)?;
+                let f = match end.map(|e| &e.ty) {
+                    Some(LexemeType::Else) => match parse_block(ls)? {
+                        (f, Some(Lexeme { ty: LexemeType::Forward, .. })) => f,
+                        (_, end) => return Err(unclosed(l, end)),
+                    },
+                    Some(LexemeType::Forward) => vec![],
+                    _ => return Err(unclosed(l, end)),
+                };
+                ast.push(l.node(Token::If((t, f))));
             }
-            _ => (),
+            LexemeType::Do => match parse_block(ls)? {
+                (body, Some(Lexeme { ty: LexemeType::Loop, .. })) => ast.push(l.node(Token::Do(body))),
+                (_, end) => return Err(unclosed(l, end)),
+            },
+            LexemeType::Begin => match parse_block(ls)? {
+                (cond, Some(Lexeme { ty: LexemeType::While, .. })) => match parse_block(ls)? {
+                    (body, Some(Lexeme { ty: LexemeType::Repeat, .. })) => {
+                        ast.push(l.node(Token::While((cond, body))))
+                    }
+                    (_, end) => return Err(unclosed(l, end)),
+                },
+                (body, Some(Lexeme { ty: LexemeType::Until, .. })) => ast.push(l.node(Token::Until(body))),
+                (_, end) => return Err(unclosed(l, end)),
+            },
+            LexemeType::Else
+            | LexemeType::Forward
+            | LexemeType::Loop
+            | LexemeType::While
+            | LexemeType::Repeat
+            | LexemeType::Until => return Ok((ast, Some(l))),
+            _ => ast.push(l.node(Token::parse(l)?)),
+        }
+    }
+    Ok((ast, None))
+}
+
+fn resolve_words(ast: &Ast) -> HashMap<String, Ast> {
+    let mut words = HashMap::new();
+    for node in ast {
+        if let Token::Definition((name, ast, _)) = &node.token {
+            words.insert(name.to_string(), ast.clone());
         }
     }
     words
@@ -73,38 +258,65 @@ pub enum Token {
     Word(String),
     Data(A),
     Builtin(Builtins),
-    Definition((String, Ast)),
+    Definition((String, Ast, Option<Effect>)),
+    If((Ast, Ast)),
+    Do(Ast),
+    While((Ast, Ast)),
+    Until(Ast),
 }
 
 impl Token {
-    fn parse(l: &Lexeme) -> Result<Self, Box<dyn Error>> {
+    fn parse(l: &Lexeme) -> Result<Self, Error> {
         let t = match l.ty {
             LexemeType::Number => {
-                let x = l.string.parse::<f64>()?;
+                let x = l.string.parse::<f64>().map_err(|_| l.error(ErrorType::Parse))?;
                 Token::Data(A::from_num(x))
             }
             LexemeType::Str => Token::Data(A::from_str(&l.string)),
             LexemeType::Array => {
-                let ws = l.string.split_whitespace();
-                let xs: Result<Vec<_>, _> = ws.map(|w| w.parse::<f64>()).into_iter().collect();
-                let xs = xs?;
-                Token::Data(A::from_nums(&xs))
+                let mut cells = vec![];
+                for item in lex(&l.string, l.inner())? {
+                    match Token::parse(&item)? {
+                        Token::Data(a) => cells.push(a),
+                        _ => return Err(item.error(ErrorType::Parse)),
+                    }
+                }
+                Token::Data(A::from_cells(vec![cells.len()], cells))
+            }
+            LexemeType::Boxed => match &lex(&l.string, l.inner())?[..] {
+                [item] => match Token::parse(item)? {
+                    Token::Data(a) => Token::Data(A::scalar(Prim::Boxed(Box::new(a)))),
+                    _ => return Err(item.error(ErrorType::Parse)),
+                },
+                _ => return Err(l.error(ErrorType::Parse)),
+            },
+            LexemeType::Quotation => {
+                let ast = parse_nested(&l.string, l.inner())?;
+                Token::Data(A::scalar(Prim::Quote(Rc::new(ast))))
             }
             LexemeType::Definition => {
-                let ws = lex(&l.string)?;
-                if let Some((name, definition)) = ws.split_first() {
-                    Token::Definition((name.string.clone(), parse_ast(definition)?))
-                } else {
-                    return Err(Box::new(ErrorType::Parse));
-                }
+                let name = l.string.split_whitespace().next();
+                let name = name.ok_or_else(|| l.error(ErrorType::Parse))?;
+                let split = l.string.find(name).unwrap_or(0) + name.len();
+                let (effect, body) = Effect::parse(&l.string[split..]).map_err(|e| l.error(e))?;
+                let head = &l.string[..l.string.len() - body.len()];
+                let start = Cursor::advance(l.inner(), head);
+                Token::Definition((name.to_string(), parse_nested(body, start)?, effect))
             }
             LexemeType::Word => Token::Word(l.string.clone()),
+            LexemeType::If
+            | LexemeType::Else
+            | LexemeType::Forward
+            | LexemeType::Do
+            | LexemeType::Loop
+            | LexemeType::Begin
+            | LexemeType::While
+            | LexemeType::Repeat
+            | LexemeType::Until => return Err(l.error(ErrorType::Parse)),
             LexemeType::Print => Token::Builtin(Builtins::Print),
-            LexemeType::If => Token::Builtin(Builtins::If),
-            LexemeType::Do => Token::Builtin(Builtins::Do),
+            LexemeType::Index => Token::Builtin(Builtins::Index),
             LexemeType::And => Token::Builtin(Builtins::And),
             LexemeType::Or => Token::Builtin(Builtins::Or),
-            LexemeType::Forward => Token::Builtin(Builtins::Forward),
             LexemeType::Plus => Token::Builtin(Builtins::Plus),
             LexemeType::Equal => Token::Builtin(Builtins::Equal),
             LexemeType::Minus => Token::Builtin(Builtins::Minus),
@@ -112,6 +324,20 @@ impl Token {
             LexemeType::Divide => Token::Builtin(Builtins::Divide),
             LexemeType::Concat => Token::Builtin(Builtins::Concat),
             LexemeType::Len => Token::Builtin(Builtins::Len),
+            LexemeType::Shape => Token::Builtin(Builtins::Shape),
+            LexemeType::Reshape => Token::Builtin(Builtins::Reshape),
+            LexemeType::Ravel => Token::Builtin(Builtins::Ravel),
+            LexemeType::Transpose => Token::Builtin(Builtins::Transpose),
+            LexemeType::Permute => Token::Builtin(Builtins::Permute),
+            LexemeType::Enclose => Token::Builtin(Builtins::Enclose),
+            LexemeType::Disclose => Token::Builtin(Builtins::Disclose),
+            LexemeType::Call => Token::Builtin(Builtins::Call),
+            LexemeType::Reduce => Token::Builtin(Builtins::Reduce),
+            LexemeType::Scan => Token::Builtin(Builtins::Scan),
+            LexemeType::Each => Token::Builtin(Builtins::Each),
+            LexemeType::Rank => Token::Builtin(Builtins::Rank),
+            LexemeType::Outer => Token::Builtin(Builtins::Outer),
+            LexemeType::Inner => Token::Builtin(Builtins::Inner),
             LexemeType::Transmute => Token::Builtin(Builtins::Transmute),
             LexemeType::Duplicate => Token::Builtin(Builtins::Duplicate),
             LexemeType::Swap => Token::Builtin(Builtins::Swap),
@@ -125,7 +351,7 @@ impl Token {
     }
 }
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum Builtins {
     Print,
     Plus,
@@ -135,6 +361,20 @@ pub enum Builtins {
     Divide,
     Concat,
     Len,
+    Shape,
+    Reshape,
+    Ravel,
+    Transpose,
+    Permute,
+    Enclose,
+    Disclose,
+    Call,
+    Reduce,
+    Scan,
+    Each,
+    Rank,
+    Outer,
+    Inner,
     Transmute,
     Duplicate,
     Swap,
@@ -142,106 +382,499 @@ pub enum Builtins {
     Clear,
     ClearButOne,
     Pop,
-    If,
-    Do,
+    Index,
     And,
     Or,
-    Forward,
 }
 
-fn lex(buf: &str) -> Result<Vec<Lexeme>, ErrorType> {
+impl Builtins {
+    /// The keyword the builtin is written as.
+    pub fn name(&self) -> &'static str {
+        use Builtins::*;
+        match self {
+            Print => "_",
+            Plus => "+",
+            Equal => "eql",
+            Minus => "-",
+            Multiply => "*",
+            Divide => "/",
+            Concat => "cat",
+            Len => "len",
+            Shape => "shp",
+            Reshape => "rsh",
+            Ravel => "rav",
+            Transpose => "tsp",
+            Permute => "prm",
+            Enclose => "enc",
+            Disclose => "dsc",
+            Call => "call",
+            Reduce => "red",
+            Scan => "scn",
+            Each => "ech",
+            Rank => "rnk",
+            Outer => "out",
+            Inner => "dot",
+            Transmute => "trm",
+            Duplicate => "dup",
+            Swap => "swp",
+            Rotate => "rot",
+            Clear => "clr",
+            ClearButOne => "clr1",
+            Pop => "pop",
+            Index => "i",
+            And => "and",
+            Or => "or",
+        }
+    }
+
+    /// The number of values the builtin takes from the stack. Builtins that
+    /// work on whatever is there, like `clr`, take none, and `rnk` takes at
+    /// least its ranks and quotation.
+    pub fn arity(&self) -> usize {
+        use Builtins::*;
+        match self {
+            Print | Rotate | Clear | ClearButOne | Index => 0,
+            Len | Shape | Ravel | Transpose | Enclose | Disclose | Call | Transmute | Duplicate
+            | Pop => 1,
+            Plus | Equal | Minus | Multiply | Divide | Concat | Reshape | Permute | Reduce
+            | Scan | Each | Rank | Swap | And | Or => 2,
+            Outer => 3,
+            Inner => 4,
+        }
+    }
+
+    /// The number of values the builtin takes and leaves, unless that depends
+    /// on the values themselves.
+    pub fn effect(&self) -> Option<(usize, usize)> {
+        use Builtins::*;
+        let outs = match self {
+            Call | Rank | Transmute | Clear | ClearButOne => return None,
+            Print | Rotate | Pop => 0,
+            Len | Shape | Duplicate | Swap => 2,
+            _ => 1,
+        };
+        Some((self.arity(), outs))
+    }
+}
+
+/// Write the tokens of `ast` separated by spaces, with a trailing space.
+pub(crate) fn write_ast(f: &mut fmt::Formatter, ast: &Ast) -> fmt::Result {
+    for node in ast {
+        write!(f, "{} ", node)?;
+    }
+    Ok(())
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Token::Word(name) => write!(f, "{}", name),
+            Token::Data(a) => write!(f, "{}", a),
+            Token::Builtin(b) => write!(f, "{}", b.name()),
+            Token::Definition((name, ast, effect)) => {
+                write!(f, ": {} ", name)?;
+                if let Some(effect) = effect {
+                    write!(f, "{} ", effect)?;
+                }
+                write_ast(f, ast)?;
+                write!(f, ";")
+            }
+            Token::If((t, e)) => {
+                write!(f, "if ")?;
+                write_ast(f, t)?;
+                if !e.is_empty() {
+                    write!(f, "else ")?;
+                    write_ast(f, e)?;
+                }
+                write!(f, "then")
+            }
+            Token::Do(body) => {
+                write!(f, "do ")?;
+                write_ast(f, body)?;
+                write!(f, "loop")
+            }
+            Token::While((cond, body)) => {
+                write!(f, "begin ")?;
+                write_ast(f, cond)?;
+                write!(f, "while ")?;
+                write_ast(f, body)?;
+                write!(f, "repeat")
+            }
+            Token::Until(body) => {
+                write!(f, "begin ")?;
+                write_ast(f, body)?;
+                write!(f, "until")
+            }
+        }
+    }
+}
+
+/// The width up to which `Token::pretty` keeps code on one line.
+const PRETTY_WIDTH: usize = 72;
+
+impl Token {
+    /// The token as source, like its `Display`, but with the bodies of
+    /// definitions and control structures indented on lines of their own if
+    /// it doesn't fit on one line.
+    pub fn pretty(&self) -> String {
+        let flat = self.to_string();
+        if flat.len() <= PRETTY_WIDTH && !flat.contains('\n') {
+            return flat;
+        }
+        let mut layout = Layout::default();
+        layout.token(self);
+        layout.newline();
+        layout.lines.join("\n")
+    }
+}
+
+/// Source being laid out over lines by `Token::pretty`.
+#[derive(Default)]
+struct Layout {
+    lines: Vec<String>,
+    line: String,
+    indent: usize,
+}
+
+impl Layout {
+    fn word(&mut self, s: &str) {
+        if self.line.is_empty() {
+            self.line = "  ".repeat(self.indent);
+        } else {
+            self.line.push(' ');
+        }
+        self.line.push_str(s);
+    }
+
+    fn newline(&mut self) {
+        if !self.line.is_empty() {
+            self.lines.push(std::mem::take(&mut self.line));
+        }
+    }
+
+    /// Lay out `ast` on lines of its own, one level deeper.
+    fn body(&mut self, ast: &Ast) {
+        self.indent += 1;
+        self.newline();
+        for node in ast {
+            self.token(&node.token);
+        }
+        self.newline();
+        self.indent -= 1;
+    }
+
+    fn token(&mut self, token: &Token) {
+        match token {
+            Token::Definition((name, ast, effect)) => {
+                self.word(":");
+                self.word(name);
+                if let Some(effect) = effect {
+                    self.word(&effect.to_string());
+                }
+                self.indent += 1;
+                self.newline();
+                for node in ast {
+                    self.token(&node.token);
+                }
+                self.indent -= 1;
+                self.word(";");
+            }
+            Token::If((t, e)) => {
+                self.word("if");
+                self.body(t);
+                if !e.is_empty() {
+                    self.word("else");
+                    self.body(e);
+                }
+                self.word("then");
+            }
+            Token::Do(body) => {
+                self.word("do");
+                self.body(body);
+                self.word("loop");
+            }
+            Token::While((cond, body)) => {
+                self.word("begin");
+                self.body(cond);
+                self.word("while");
+                self.body(body);
+                self.word("repeat");
+            }
+            Token::Until(body) => {
+                self.word("begin");
+                self.body(body);
+                self.word("until");
+            }
+            _ => self.word(&token.to_string()),
+        }
+    }
+}
+
+/// The words with a meaning of their own, and the lexemes they make.
+const KEYWORDS: &[(&str, LexemeType)] = &[
+    ("and", LexemeType::And),
+    ("or", LexemeType::Or),
+    ("+", LexemeType::Plus),
+    ("-", LexemeType::Minus),
+    ("*", LexemeType::Multiply),
+    ("/", LexemeType::Divide),
+    ("cat", LexemeType::Concat),
+    ("len", LexemeType::Len),
+    ("shp", LexemeType::Shape),
+    ("rsh", LexemeType::Reshape),
+    ("rav", LexemeType::Ravel),
+    ("tsp", LexemeType::Transpose),
+    ("prm", LexemeType::Permute),
+    ("enc", LexemeType::Enclose),
+    ("dsc", LexemeType::Disclose),
+    ("call", LexemeType::Call),
+    ("red", LexemeType::Reduce),
+    ("scn", LexemeType::Scan),
+    ("ech", LexemeType::Each),
+    ("rnk", LexemeType::Rank),
+    ("out", LexemeType::Outer),
+    ("dot", LexemeType::Inner),
+    ("trm", LexemeType::Transmute),
+    ("dup", LexemeType::Duplicate),
+    ("pop", LexemeType::Pop),
+    ("swp", LexemeType::Swap),
+    ("rot", LexemeType::Rotate),
+    ("clr", LexemeType::Clear),
+    ("clr1", LexemeType::ClearButOne),
+    ("eql", LexemeType::Equal),
+    ("do", LexemeType::Do),
+    ("loop", LexemeType::Loop),
+    ("begin", LexemeType::Begin),
+    ("while", LexemeType::While),
+    ("repeat", LexemeType::Repeat),
+    ("until", LexemeType::Until),
+    ("i", LexemeType::Index),
+    ("if", LexemeType::If),
+    ("else", LexemeType::Else),
+    ("then", LexemeType::Forward),
+    ("_", LexemeType::Print),
+];
+
+/// The keywords of the language, builtins as well as control flow.
+pub fn keywords() -> impl Iterator<Item = &'static str> {
+    KEYWORDS.iter().map(|(k, _)| *k)
+}
+
+/// Characters of the source being lexed, keeping track of the position of the
+/// next one.
+#[derive(Clone)]
+struct Cursor<'a> {
+    cs: Peekable<Chars<'a>>,
+    pos: Span,
+}
+
+impl<'a> Cursor<'a> {
+    fn new(buf: &'a str, start: Span) -> Self {
+        Self {
+            cs: buf.chars().peekable(),
+            pos: start,
+        }
+    }
+
+    /// The position `start` moves to after the characters of `s`.
+    fn advance(start: Span, s: &str) -> Span {
+        let mut cs = Cursor::new(s, start);
+        cs.by_ref().for_each(drop);
+        cs.pos
+    }
+
+    fn peek(&mut self) -> Option<&char> {
+        self.cs.peek()
+    }
+
+    /// The span from `start` up to the next character.
+    fn since(&self, start: &Span) -> Span {
+        let len = if self.pos.line == start.line {
+            self.pos.col - start.col
+        } else {
+            1
+        };
+        Span {
+            len,
+            ..start.clone()
+        }
+    }
+}
+
+impl Iterator for Cursor<'_> {
+    type Item = char;
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.cs.next()?;
+        if c == '\n' {
+            self.pos.line += 1;
+            self.pos.col = 1;
+        } else {
+            self.pos.col += 1;
+        }
+        Some(c)
+    }
+}
+
+/// Consume a bracketed section starting at `open`, returning everything up to
+/// the matching `close`. Nested brackets and brackets inside strings are
+/// skipped over.
+fn delimited(cs: &mut Cursor, open: char, close: char) -> Result<String, ErrorType> {
+    cs.next();
+    let mut depth = 1;
+    let mut quoted = false;
+    let mut l = String::new();
+    for cm in cs.by_ref() {
+        match cm {
+            '"' => quoted = !quoted,
+            c if c == open && !quoted => depth += 1,
+            c if c == close && !quoted => {
+                depth -= 1;
+                if depth == 0 {
+                    return Ok(l);
+                }
+            }
+            _ => {}
+        }
+        l.push(cm);
+    }
+    Err(ErrorType::Incomplete)
+}
+
+/// Whether the `<` next in `cs` opens a boxed literal rather than starting a
+/// word, which it does when a literal follows right after it.
+fn opens_box(cs: &Cursor) -> bool {
+    let mut ahead = cs.cs.clone().skip(1);
+    match ahead.next() {
+        Some('[' | '{' | '"' | '<') => true,
+        Some('-') => ahead.next().is_some_and(|c| c.is_ascii_digit()),
+        Some(c) => c.is_ascii_digit(),
+        None => false,
+    }
+}
+
+/// Consume a boxed literal starting at `<`, returning the literal inside up
+/// to the matching `>`. Anything inside brackets or strings is skipped over,
+/// so that words like `>` in a boxed quotation don't end it.
+fn boxed(cs: &mut Cursor) -> Result<String, ErrorType> {
+    cs.next();
+    let mut boxes = 1;
+    let mut depth = 0;
+    let mut quoted = false;
+    let mut l = String::new();
+    for cm in cs.by_ref() {
+        match cm {
+            '"' => quoted = !quoted,
+            '[' | '{' if !quoted => depth += 1,
+            ']' | '}' if !quoted => depth -= 1,
+            '<' if !quoted && depth == 0 => boxes += 1,
+            '>' if !quoted && depth == 0 => {
+                boxes -= 1;
+                if boxes == 0 {
+                    return Ok(l);
+                }
+            }
+            _ => {}
+        }
+        l.push(cm);
+    }
+    Err(ErrorType::Incomplete)
+}
+
+fn lex(buf: &str, start: Span) -> Result<Vec<Lexeme>, Error> {
     use LexemeType::*;
 
-    let mut cs = buf.chars().peekable();
+    let mut cs = Cursor::new(buf, start);
     let mut res = vec![];
-    while let Some(c) = cs.peek() {
+    while let Some(&c) = cs.peek() {
+        let start = cs.pos.clone();
+        let unclosed = |c: char| Error::new(ErrorType::Incomplete, &start, &c.to_string());
         match c {
             '#' => {
-                while let Some(cm) = cs.next() {
+                for cm in cs.by_ref() {
                     if cm == '\n' {
                         break;
                     }
                 }
+                continue;
             }
             '"' => {
                 cs.next();
-                let p = cs.clone().position(|c| c == '"').ok_or(ErrorType::Parse)?;
-                let l: String = cs.clone().collect::<Vec<_>>()[..p].into_iter().collect();
-                while let Some(cm) = cs.next() {
+                let p = cs.clone().position(|c| c == '"').ok_or_else(|| unclosed('"'))?;
+                let l: String = cs.clone().take(p).collect();
+                for cm in cs.by_ref() {
                     if cm == '"' {
                         break;
                     }
                 }
-                res.push(Lexeme::new(l.trim(), Str));
+                res.push(Lexeme::new(l.trim(), Str, cs.since(&start)));
             }
             '[' => {
-                let p = cs.clone().position(|c| c == ']').ok_or(ErrorType::Parse)?;
-                let l: String = cs.clone().collect::<Vec<_>>()[1..p - 1]
-                    .into_iter()
-                    .collect();
-                while let Some(cm) = cs.next() {
-                    if cm == ']' {
-                        break;
-                    }
-                }
-                res.push(Lexeme::new(l.trim(), Array));
+                let l = delimited(&mut cs, '[', ']').map_err(|_| unclosed('['))?;
+                res.push(Lexeme::new(&l, Array, cs.since(&start)));
+                continue;
+            }
+            '<' if opens_box(&cs) => {
+                let l = boxed(&mut cs).map_err(|_| unclosed('<'))?;
+                res.push(Lexeme::new(&l, Boxed, cs.since(&start)));
+                continue;
+            }
+            '{' => {
+                let l = delimited(&mut cs, '{', '}').map_err(|_| unclosed('{'))?;
+                res.push(Lexeme::new(&l, Quotation, cs.since(&start)));
+                continue;
             }
             ':' => {
-                let p = cs.clone().position(|c| c == ';').ok_or(ErrorType::Parse)?;
-                let l: String = cs.clone().collect::<Vec<_>>()[1..p - 1]
-                    .into_iter()
-                    .collect();
-                while let Some(cm) = cs.next() {
-                    if cm == ';' {
-                        break;
+                cs.next();
+                let mut quoted = false;
+                let mut comment = false;
+                let mut l = String::new();
+                loop {
+                    let cm = cs.next().ok_or_else(|| unclosed(':'))?;
+                    match cm {
+                        '\n' => comment = false,
+                        '#' if !quoted && l.ends_with(char::is_whitespace) => comment = true,
+                        '"' if !comment => quoted = !quoted,
+                        ';' if !quoted && !comment => break,
+                        _ => {}
                     }
+                    l.push(cm);
                 }
-                res.push(Lexeme::new(l.trim(), Definition));
+                res.push(Lexeme::new(&l, Definition, cs.since(&start)));
+                continue;
             }
-            a if a.is_digit(10) => {
-                let p = cs.clone().position(|c| c.is_whitespace()).unwrap_or(1);
-                let l: String = cs.clone().collect::<Vec<_>>()[..p].into_iter().collect();
+            a if a.is_ascii_digit() => {
+                let mut l = String::new();
                 while let Some(cm) = cs.peek() {
                     if !cm.is_whitespace() {
+                        l.push(*cm);
                         cs.next();
                     } else {
                         break;
                     }
                 }
-                res.push(Lexeme::new(l.trim(), Number));
+                res.push(Lexeme::new(&l, Number, cs.since(&start)));
             }
             a if !a.is_whitespace() => {
                 let mut s = String::new();
                 while let Some(cm) = cs.peek() {
                     if !cm.is_whitespace() {
-                        s.push(cs.next().ok_or(ErrorType::Parse)?);
+                        s.push(*cm);
+                        cs.next();
                     } else {
                         break;
                     }
                 }
-                match s.as_ref() {
-                    "and" => res.push(Lexeme::new("and", And)),
-                    "or" => res.push(Lexeme::new("or", Or)),
-                    "+" => res.push(Lexeme::new("+", Plus)),
-                    "-" => res.push(Lexeme::new("-", Minus)),
-                    "*" => res.push(Lexeme::new("*", Multiply)),
-                    "/" => res.push(Lexeme::new("/", Divide)),
-                    "cat" => res.push(Lexeme::new("cat", Concat)),
-                    "len" => res.push(Lexeme::new("len", Len)),
-                    "trm" => res.push(Lexeme::new("trm", Transmute)),
-                    "dup" => res.push(Lexeme::new("dup", Duplicate)),
-                    "pop" => res.push(Lexeme::new("pop", Pop)),
-                    "swp" => res.push(Lexeme::new("swp", Swap)),
-                    "rot" => res.push(Lexeme::new("rot", Rotate)),
-                    "clr" => res.push(Lexeme::new("clr", Clear)),
-                    "clr1" => res.push(Lexeme::new("clr1", ClearButOne)),
-                    "eql" => res.push(Lexeme::new("eql", Equal)),
-                    "do" => res.push(Lexeme::new("do", Do)),
-                    "if" => res.push(Lexeme::new("if", If)),
-                    "then" => res.push(Lexeme::new("then", Forward)),
-                    "_" => res.push(Lexeme::new("_", Print)),
-                    _ => res.push(Lexeme::new(s.trim(), Word)),
-                }
+                let keyword = KEYWORDS.iter().find(|(k, _)| *k == s).map(|(_, ty)| *ty);
+                let ty = match keyword {
+                    Some(ty) => ty,
+                    // Numbers that aren't finite are shown as `inf`, `-inf`
+                    // and `NaN`, so those read back as numbers too.
+                    None if s.starts_with('-') && s.parse::<f64>().is_ok() => Number,
+                    None if s == "inf" || s == "NaN" => Number,
+                    None => Word,
+                };
+                res.push(Lexeme::new(&s, ty, cs.since(&start)));
             }
             _ => {}
         }
@@ -251,9 +884,150 @@ fn lex(buf: &str) -> Result<Vec<Lexeme>, ErrorType> {
     Ok(res)
 }
 
-pub fn parse(buf: &str) -> Result<(Ast, HashMap<String, Ast>), Box<dyn Error>> {
-    let lexemes = lex(&buf);
+/// Whether `buf` ends in the middle of a string, element, quotation,
+/// definition or block, so that more input could still complete it.
+pub fn is_incomplete(buf: &str) -> bool {
+    match parse(buf, "") {
+        Err(e) => matches!(e.ty, ErrorType::Incomplete),
+        Ok(_) => false,
+    }
+}
+
+/// Parse the source `buf`, naming `file` as its origin in the spans of the
+/// result.
+pub fn parse(buf: &str, file: &str) -> Result<(Ast, HashMap<String, Ast>), Error> {
+    let start = Span {
+        file: Rc::from(file),
+        line: 1,
+        col: 1,
+        len: 0,
+    };
+    let lexemes = lex(buf, start);
     let ast = parse_ast(&lexemes?)?;
     let words = resolve_words(&ast);
     Ok((ast, words))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The value of the literal `src`.
+    fn literal(src: &str) -> A {
+        let (ast, _) = parse(src, "").unwrap();
+        match &ast[..] {
+            [Node { token: Token::Data(a), .. }] => a.clone(),
+            _ => panic!("`{}` isn't a single literal", src),
+        }
+    }
+
+    #[test]
+    fn parts_of_the_same_shape_are_joined() {
+        let m = literal("[ [ 1 2 ] [ 3 4 ] ]");
+        assert_eq!(m.shape, vec![2, 2]);
+        assert_eq!(literal(r#"[ "ab" "cd" ]"#).shape, vec![2, 2]);
+    }
+
+    #[test]
+    fn ragged_parts_are_boxed() {
+        let a = literal(r#"[ 1 "ab" [ 2 3 4 ] ]"#);
+        assert_eq!(a.shape, vec![3]);
+        assert_eq!(a.d[0], Prim::Num(1.));
+        assert_eq!(a.d[1], Prim::Boxed(Box::new(A::from_str("ab"))));
+        assert_eq!(a.d[2], Prim::Boxed(Box::new(A::from_nums(&[2., 3., 4.]))));
+    }
+
+    #[test]
+    fn boxes_read_back_as_they_are_shown() {
+        // Single characters on their own have no literal, so boxes of them are
+        // the only ones that can't be read back.
+        for src in [r#"[ 1 "ab" ]"#, "<[ 1 2 3 ]>", "<<5>>", "[ <[ 1 2 ]> <[ 3 4 ]> ]", "<{ 1 > }>"] {
+            let a = literal(src);
+            assert_eq!(literal(&a.to_string()), a, "`{}` shown as `{}`", src, a);
+        }
+        assert_eq!(literal("[ <[ 1 2 ]> <[ 3 4 ]> ]").shape, vec![2]);
+
+        let c = A::scalar(Prim::Boxed(Box::new(A::scalar(Prim::Char('b')))));
+        assert_eq!(c.to_string(), "<'b'>");
+        assert!(matches!(&tokens(&c.to_string())[..], [Token::Word(_)]));
+    }
+
+    /// The tokens of `src`.
+    fn tokens(src: &str) -> Vec<Token> {
+        parse(src, "").unwrap().0.into_iter().map(|n| n.token).collect()
+    }
+
+    #[test]
+    fn conditionals_nest() {
+        let ast = tokens("if if 1 else 2 then else 3 then 4");
+        match &ast[..] {
+            [Token::If((t, f)), Token::Data(_)] => {
+                assert!(matches!(&t[..], [Node { token: Token::If((t, f)), .. }]
+                    if t.len() == 1 && f.len() == 1));
+                assert_eq!(f.len(), 1);
+            }
+            _ => panic!("unexpected {:?}", ast),
+        }
+        assert!(matches!(&tokens("if 1 then")[..], [Token::If((t, f))] if t.len() == 1 && f.is_empty()));
+    }
+
+    #[test]
+    fn unmatched_conditionals_are_errors() {
+        for src in ["1 then", "1 else 2 then", "if 1 else 2 else 3 then"] {
+            assert!(parse(src, "").is_err(), "`{}` parsed", src);
+        }
+    }
+
+    #[test]
+    fn loops_hold_blocks() {
+        let ast = tokens("0 3 do i begin dup while 1 - repeat + loop begin 1 until");
+        match &ast[..] {
+            [Token::Data(_), Token::Data(_), Token::Do(body), Token::Until(u)] => {
+                assert_eq!(body.len(), 3);
+                assert!(matches!(&body[1].token, Token::While((c, b)) if c.len() == 1 && b.len() == 2));
+                assert_eq!(u.len(), 1);
+            }
+            _ => panic!("unexpected {:?}", ast),
+        }
+    }
+
+    #[test]
+    fn unmatched_loops_are_errors() {
+        for src in ["3 do i", "loop", "begin 1 while 2", "begin 1 repeat", "do begin loop until"] {
+            assert!(parse(src, "").is_err(), "`{}` parsed", src);
+        }
+    }
+
+    #[test]
+    fn open_constructs_are_incomplete() {
+        for src in [": sq dup\n", "\"ab", "[ 1 2", "{ 1", "if 1 else", "3 do i", "begin 1 while"] {
+            assert!(is_incomplete(src), "`{}` is complete", src);
+        }
+        for src in [": sq dup * ;", "1 2 +", "1 then", "]", "loop", ""] {
+            assert!(!is_incomplete(src), "`{}` is incomplete", src);
+        }
+    }
+
+    #[test]
+    fn definitions_span_lines() {
+        let (ast, words) = parse(": sq\n  dup\n  * ;\n2 sq", "").unwrap();
+        assert_eq!(words["sq"].len(), 2);
+        assert_eq!(ast.last().unwrap().span.line, 4);
+    }
+
+    #[test]
+    fn parentheses_only_annotate_definitions() {
+        let (ast, _) = parse(": sq ( a -- b ) dup * ;", "").unwrap();
+        match &ast[0].token {
+            Token::Definition((_, body, Some(effect))) => {
+                assert_eq!((effect.ins.len(), effect.outs.len()), (1, 1));
+                assert_eq!(body.len(), 2);
+                assert_eq!(body[0].span.col, 17);
+            }
+            t => panic!("unexpected {:?}", t),
+        }
+        assert!(matches!(&tokens("( 1 )")[0], Token::Word(w) if w == "("));
+        assert!(parse(": sq ( a b ) ;", "").is_err());
+        assert!(parse(": sq ( a -- ;", "").is_err());
+    }
+}
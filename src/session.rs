@@ -0,0 +1,233 @@
+//! Saving the words and the stack of an interpreter to text and reading them
+//! back.
+//!
+//! A session starts with the nd source defining the words, after a `#words`
+//! line giving its length in bytes. The values on the stack follow a `#stack`
+//! line giving their number, bottom first. Values are written exactly, as not
+//! every value can be written as an nd literal: a value is its shape in
+//! parentheses followed by its elements, which are numbers, characters as `c`
+//! and their code point, boxed values in `<` and `>`, or quotations as `q`,
+//! the length of their source in bytes, `:` and the source.
+
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::rc::Rc;
+
+use crate::array::{Prim, A};
+use crate::errors::{Error, ErrorType, Span};
+use crate::parser::{self, Ast, Effect, Token};
+
+/// The first line of every session.
+const HEADER: &str = "# nd session";
+/// What the line before the source of the words starts with.
+const WORDS: &str = "#words ";
+/// What the line before the values on the stack starts with.
+const STACK: &str = "#stack ";
+
+pub(crate) fn write(
+    stack: &[A],
+    words: &HashMap<String, Ast>,
+    effects: &HashMap<String, Effect>,
+) -> String {
+    let mut source = String::new();
+    let mut names: Vec<&String> = words.keys().collect();
+    names.sort();
+    for name in names {
+        let definition = Token::Definition((
+            name.clone(),
+            words[name].clone(),
+            effects.get(name).cloned(),
+        ));
+        source.push_str(&definition.pretty());
+        source.push('\n');
+    }
+
+    let mut out = format!("{}\n{}{}\n{}", HEADER, WORDS, source.len(), source);
+    writeln!(out, "{}{}", STACK, stack.len()).unwrap();
+    for a in stack {
+        write_value(&mut out, a);
+        out.push('\n');
+    }
+    out
+}
+
+/// What a session holds: the code defining its words, as `parser::parse`
+/// returns it, and the values on its stack.
+pub(crate) struct Session {
+    pub ast: Ast,
+    pub words: HashMap<String, Ast>,
+    pub stack: Vec<A>,
+}
+
+/// Read a session written by `write` from `file`.
+pub(crate) fn read(session: &str, file: &str) -> Result<Session, Error> {
+    let not_session = || ErrorType::Msg("Not a session saved by nd.");
+    let count = |s: &str, line: &str| -> Option<(usize, usize)> {
+        let rest = s.strip_prefix(line)?;
+        let (n, rest) = rest.split_once('\n')?;
+        Some((n.parse().ok()?, s.len() - rest.len()))
+    };
+
+    let rest = session.strip_prefix(HEADER).and_then(|s| s.strip_prefix('\n'));
+    let rest = rest.ok_or_else(not_session)?;
+    let (len, skip) = count(rest, WORDS).ok_or_else(not_session)?;
+    let end = session.len() - rest.len() + skip + len;
+
+    // The lines before the words are comments, so parsing them along with
+    // the words keeps the lines of errors those of the session.
+    let source = session.get(..end).ok_or_else(not_session)?;
+    let (ast, words) = parser::parse(source, file)?;
+
+    let (n, skip) = count(&session[end..], STACK).ok_or_else(not_session)?;
+    let mut rest = &session[end + skip..];
+    let mut stack = vec![];
+    for _ in 0..n {
+        let start = session.len() - rest.trim_start().len();
+        match read_value(&mut rest, file) {
+            Some(a) => stack.push(a),
+            None => return Err(invalid(session, start, file)),
+        }
+    }
+    if !rest.trim().is_empty() {
+        return Err(invalid(session, session.len() - rest.trim_start().len(), file));
+    }
+    Ok(Session { ast, words, stack })
+}
+
+/// The error for the value starting at byte `start` of `session`, which
+/// couldn't be read.
+fn invalid(session: &str, start: usize, file: &str) -> Error {
+    let before = &session[..start];
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    let token = session[start..].lines().next().unwrap_or("");
+    let span = Span {
+        file: Rc::from(file),
+        line: before.matches('\n').count() + 1,
+        col: session[line_start..start].chars().count() + 1,
+        len: token.chars().count(),
+    };
+    Error::new(ErrorType::Msg("Invalid value in session."), &span, token)
+}
+
+fn write_value(out: &mut String, a: &A) {
+    let shape: Vec<String> = a.shape.iter().map(|n| n.to_string()).collect();
+    write!(out, "({})", shape.join(" ")).unwrap();
+    for x in &a.d {
+        out.push(' ');
+        match x {
+            Prim::Num(x) => write!(out, "{}", x).unwrap(),
+            Prim::Char(c) => write!(out, "c{}", *c as u32).unwrap(),
+            Prim::Boxed(a) => {
+                out.push('<');
+                write_value(out, a);
+                out.push('>');
+            }
+            Prim::Quote(_) => {
+                let source = x.to_string();
+                write!(out, "q{}:{}", source.len(), source).unwrap();
+            }
+        }
+    }
+}
+
+/// Read a value from the start of `s`, leaving the rest of it in `s`.
+fn read_value(s: &mut &str, file: &str) -> Option<A> {
+    let (dims, rest) = s.trim_start().strip_prefix('(')?.split_once(')')?;
+    let shape = dims
+        .split_whitespace()
+        .map(|n| n.parse().ok())
+        .collect::<Option<Vec<usize>>>()?;
+    *s = rest;
+
+    let n = shape.iter().try_fold(1usize, |n, &len| n.checked_mul(len))?;
+    let mut d = vec![];
+    for _ in 0..n {
+        d.push(read_prim(s, file)?);
+    }
+    A::try_new(shape, d).ok()
+}
+
+fn read_prim(s: &mut &str, file: &str) -> Option<Prim> {
+    *s = s.trim_start();
+    if let Some(rest) = s.strip_prefix('<') {
+        *s = rest;
+        let a = read_value(s, file)?;
+        *s = s.trim_start().strip_prefix('>')?;
+        return Some(Prim::Boxed(Box::new(a)));
+    }
+    if let Some(rest) = s.strip_prefix('q') {
+        let (len, rest) = rest.split_once(':')?;
+        let len = len.parse().ok()?;
+        let source = rest.get(..len)?;
+        *s = &rest[len..];
+        let (ast, _) = parser::parse(source, file).ok()?;
+        return match ast.first().map(|node| &node.token) {
+            Some(Token::Data(a)) if ast.len() == 1 => match &a.d[..] {
+                [quote @ Prim::Quote(_)] => Some(quote.clone()),
+                _ => None,
+            },
+            _ => None,
+        };
+    }
+
+    let end = s
+        .find(|c: char| c.is_whitespace() || c == '>')
+        .unwrap_or(s.len());
+    let (token, rest) = s.split_at(end);
+    *s = rest;
+    match token.strip_prefix('c') {
+        Some(code) => char::from_u32(code.parse().ok()?).map(Prim::Char),
+        None => token.parse().ok().map(Prim::Num),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sessions_read_back_exactly() {
+        let source = ": sq ( a -- b ) dup * ;\n: text \"two\n\tlines\" ;\n: big 1e999 -1e999 ;\n";
+        let (ast, words) = parser::parse(source, "").unwrap();
+        let mut effects = HashMap::new();
+        for node in &ast {
+            if let Token::Definition((name, _, Some(effect))) = &node.token {
+                effects.insert(name.clone(), effect.clone());
+            }
+        }
+
+        let (quote, _) = parser::parse("{ \"a\n\tb\" [ [ 1 2 ] [ 3 4 ] ] <\"c\"> sq 1e999 }", "").unwrap();
+        let quote = match &quote[0].token {
+            Token::Data(a) => a.clone(),
+            t => panic!("unexpected {:?}", t),
+        };
+        let stack = vec![
+            A::from_nums(&[f64::NAN, f64::INFINITY, -0., 0.1]),
+            A::from_str("it's \"\n"),
+            A::new(vec![0, 3], vec![]),
+            A::scalar(Prim::Boxed(Box::new(A::from_nums(&[1., 2.])))),
+            quote,
+        ];
+
+        let saved = write(&stack, &words, &effects);
+        let session = read(&saved, "").unwrap();
+        assert_eq!(session.words, words);
+        let big = Token::Data(A::from_num(f64::INFINITY));
+        assert_eq!(session.words["big"][0].token, big);
+        assert!(matches!(&session.stack[0].d[0], Prim::Num(x) if x.is_nan()));
+        assert_eq!(session.stack[0].d[1..], stack[0].d[1..]);
+        assert_eq!(session.stack[1..], stack[1..]);
+        assert_eq!(write(&session.stack, &session.words, &effects), saved);
+    }
+
+    #[test]
+    fn damaged_sessions_are_errors() {
+        let saved = write(&[A::from_nums(&[1.])], &HashMap::new(), &HashMap::new());
+        assert!(read("1 2 +", "").is_err());
+        assert!(read(&saved.replace("(1) 1", "(2) 1"), "").is_err());
+        assert!(read(&saved.replace("#stack 1", "#stack 2"), "").is_err());
+        assert!(read(&format!("{}junk", saved), "").is_err());
+        let e = read(&saved.replace("(1) 1", "(1) x"), "").err().unwrap();
+        assert_eq!(e.location.unwrap().span.line, 4);
+    }
+}
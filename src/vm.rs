@@ -0,0 +1,406 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::array::A;
+use crate::builtins;
+use crate::errors::{Error, ErrorType, Span};
+use crate::eval::Native;
+use crate::parser::{Ast, Builtins, Node, Token};
+
+/// An instruction of compiled code. Jump targets and called words are
+/// addresses in the code.
+#[derive(Debug)]
+enum Op {
+    Push(A),
+    Builtin(Builtins),
+    Call(usize),
+    /// A call that ends the code it's in, which reuses the caller's frame.
+    TailCall(usize),
+    Native(usize),
+    Undefined(String),
+    Jump(usize),
+    /// Pop a value and jump if it's falsy.
+    JumpUnless(usize),
+    /// Pop the number of iterations and start a `do` loop, or jump past it if
+    /// there are none.
+    Do(usize),
+    /// Count an iteration of the innermost `do` loop, and jump back to its
+    /// start if it isn't done.
+    Loop(usize),
+    Return,
+}
+
+#[derive(Debug)]
+enum Frame {
+    /// A word or quotation being run, called from `site`.
+    Call { ret: usize, site: usize },
+    /// A `do` loop started at `site`.
+    Loop { i: usize, n: usize, site: usize },
+    /// Tail calls made since the last call, which have no frames of their
+    /// own. Only the first and the last are kept for errors.
+    Tail { first: usize, last: usize },
+}
+
+/// Compiles code to a flat list of instructions as it's needed and runs it.
+/// Words are compiled once, the first time code calling them is compiled, and
+/// quotations the first time they're called.
+pub(crate) struct Vm<'a> {
+    words: &'a HashMap<String, Ast>,
+    natives: &'a HashMap<String, Native>,
+    code: Vec<Op>,
+    /// Where each instruction came from, and the token to show in errors.
+    /// Jumps and returns the compiler adds have no source of their own.
+    sources: Vec<Option<(Span, Rc<str>)>>,
+    /// The addresses of the compiled words.
+    addrs: HashMap<String, usize>,
+    /// The natives called by the code, by the index `Op::Native` refers to.
+    called: Vec<&'a Native>,
+    /// The addresses of the compiled quotations, kept alive so that their
+    /// addresses can't be reused by other quotations.
+    quotes: HashMap<*const Ast, (Rc<Ast>, usize)>,
+    /// Calls to words that weren't compiled yet when the call was.
+    fixups: Vec<(usize, String)>,
+    frames: Vec<Frame>,
+}
+
+impl<'a> Vm<'a> {
+    pub(crate) fn new(words: &'a HashMap<String, Ast>, natives: &'a HashMap<String, Native>) -> Self {
+        Self {
+            words,
+            natives,
+            code: vec![],
+            sources: vec![],
+            addrs: HashMap::new(),
+            called: vec![],
+            quotes: HashMap::new(),
+            fixups: vec![],
+            frames: vec![],
+        }
+    }
+
+    /// Compile `ast` and the words it calls, returning its address.
+    pub(crate) fn compile(&mut self, ast: &Ast) -> usize {
+        let start = self.code.len();
+        self.block(ast);
+        self.emit(Op::Return, None);
+
+        while let Some((at, name)) = self.fixups.pop() {
+            let addr = match self.addrs.get(&name) {
+                Some(&addr) => addr,
+                None => {
+                    let addr = self.code.len();
+                    self.addrs.insert(name.clone(), addr);
+                    let words = self.words;
+                    self.block(&words[&name]);
+                    self.emit(Op::Return, None);
+                    addr
+                }
+            };
+            self.code[at] = Op::Call(addr);
+        }
+
+        self.tail_calls(start);
+        start
+    }
+
+    /// Run the quotation `op` on `stack`.
+    pub(crate) fn run(&mut self, op: &Rc<Ast>, stack: &mut Vec<A>) -> Result<(), Error> {
+        let addr = self.quote(op);
+        self.exec(addr, stack)
+    }
+
+    /// The index of the innermost `do` loop being run.
+    pub(crate) fn index(&self) -> Option<usize> {
+        self.frames.iter().rev().find_map(|f| match f {
+            Frame::Loop { i, .. } => Some(*i),
+            _ => None,
+        })
+    }
+
+    fn quote(&mut self, op: &Rc<Ast>) -> usize {
+        if let Some((_, addr)) = self.quotes.get(&Rc::as_ptr(op)) {
+            return *addr;
+        }
+        let addr = self.compile(op);
+        self.quotes.insert(Rc::as_ptr(op), (op.clone(), addr));
+        addr
+    }
+
+    fn emit(&mut self, op: Op, source: Option<(&Span, &str)>) -> usize {
+        self.code.push(op);
+        self.sources.push(source.map(|(span, token)| (span.clone(), Rc::from(token))));
+        self.code.len() - 1
+    }
+
+    /// Point the jump at `at` to the next instruction.
+    fn land(&mut self, at: usize) {
+        let here = self.code.len();
+        match &mut self.code[at] {
+            Op::Jump(to) | Op::JumpUnless(to) | Op::Do(to) => *to = here,
+            _ => unreachable!(),
+        }
+    }
+
+    fn block(&mut self, ast: &Ast) {
+        for node in ast {
+            self.node(node);
+        }
+    }
+
+    fn node(&mut self, node: &Node) {
+        use Token::*;
+
+        let at = |keyword| Some((&node.span, keyword));
+        match &node.token {
+            Word(name) => {
+                let source = at(name.as_str());
+                if let Some(&addr) = self.addrs.get(name) {
+                    self.emit(Op::Call(addr), source);
+                } else if self.words.contains_key(name) {
+                    let call = self.emit(Op::Call(usize::MAX), source);
+                    self.fixups.push((call, name.clone()));
+                } else if let Some(native) = self.natives.get(name) {
+                    self.called.push(native);
+                    self.emit(Op::Native(self.called.len() - 1), source);
+                } else {
+                    self.emit(Op::Undefined(name.clone()), source);
+                }
+            }
+            Data(a) => {
+                self.emit(Op::Push(a.clone()), at(""));
+            }
+            Builtin(b) => {
+                self.emit(Op::Builtin(*b), at(b.name()));
+            }
+            If((t, f)) => {
+                let unless = self.emit(Op::JumpUnless(0), at("if"));
+                self.block(t);
+                if f.is_empty() {
+                    self.land(unless);
+                } else {
+                    let end = self.emit(Op::Jump(0), None);
+                    self.land(unless);
+                    self.block(f);
+                    self.land(end);
+                }
+            }
+            Do(body) => {
+                let start = self.emit(Op::Do(0), at("do"));
+                self.block(body);
+                self.emit(Op::Loop(start + 1), at("do"));
+                self.land(start);
+            }
+            While((cond, body)) => {
+                let start = self.code.len();
+                self.block(cond);
+                let unless = self.emit(Op::JumpUnless(0), at("while"));
+                self.block(body);
+                self.emit(Op::Jump(start), None);
+                self.land(unless);
+            }
+            Until(body) => {
+                let start = self.code.len();
+                self.block(body);
+                self.emit(Op::JumpUnless(start), at("until"));
+            }
+            Definition(_) => {}
+        }
+    }
+
+    /// Turn calls from `start` on that are followed by nothing but jumps to a
+    /// return into tail calls.
+    fn tail_calls(&mut self, start: usize) {
+        for at in start..self.code.len() {
+            if let Op::Call(addr) = self.code[at] {
+                let mut next = at + 1;
+                while let Op::Jump(to) = self.code[next] {
+                    next = to;
+                }
+                if let Op::Return = self.code[next] {
+                    self.code[at] = Op::TailCall(addr);
+                }
+            }
+        }
+    }
+
+    /// Run the code at `addr` until it returns.
+    pub(crate) fn exec(&mut self, addr: usize, stack: &mut Vec<A>) -> Result<(), Error> {
+        let base = self.frames.len();
+        let mut pc = addr;
+        loop {
+            match self.step(pc, base, stack) {
+                Ok(Some(next)) => pc = next,
+                Ok(None) => return Ok(()),
+                Err(e) => return Err(self.unwind(e, pc, base)),
+            }
+        }
+    }
+
+    /// Run the instruction at `pc`, returning the next one to run, if any.
+    fn step(&mut self, pc: usize, base: usize, stack: &mut Vec<A>) -> Result<Option<usize>, Error> {
+        let next = match self.code[pc] {
+            Op::Push(ref a) => {
+                stack.push(a.clone());
+                pc + 1
+            }
+            // Calling a quotation is a call like any other, so that it can
+            // recurse as deep as words can.
+            Op::Builtin(Builtins::Call) if !stack.is_empty() => {
+                let op = builtins::quotation(stack.pop().unwrap())?;
+                let addr = self.quote(&op);
+                self.frames.push(Frame::Call { ret: pc + 1, site: pc });
+                addr
+            }
+            Op::Builtin(b) => {
+                b.eval(stack, self)?;
+                pc + 1
+            }
+            Op::Call(addr) => {
+                self.frames.push(Frame::Call { ret: pc + 1, site: pc });
+                addr
+            }
+            Op::TailCall(addr) => {
+                let above = self.frames.len() > base;
+                match self.frames.last_mut() {
+                    Some(Frame::Tail { last, .. }) if above => *last = pc,
+                    _ => self.frames.push(Frame::Tail { first: pc, last: pc }),
+                }
+                addr
+            }
+            Op::Native(i) => {
+                self.called[i].eval(self.token(pc), stack)?;
+                pc + 1
+            }
+            Op::Undefined(ref name) => return Err(ErrorType::Undefined(name.clone()).into()),
+            Op::Jump(to) => to,
+            Op::JumpUnless(to) => {
+                let a = stack.pop().ok_or_else(|| self.underflow(pc))?;
+                if builtins::iff(a)? {
+                    pc + 1
+                } else {
+                    to
+                }
+            }
+            Op::Do(end) => {
+                let a = stack.pop().ok_or_else(|| self.underflow(pc))?;
+                let n = a.d.first().and_then(|x| x.as_f64());
+                let n = n.filter(|n| *n >= 0. && n.fract() == 0.).ok_or(ErrorType::Msg(
+                    "Couldn't loop, the count of `do` must be a non-negative integer.",
+                ))?;
+                if n < 1. {
+                    end
+                } else {
+                    self.frames.push(Frame::Loop { i: 0, n: n as usize, site: pc });
+                    pc + 1
+                }
+            }
+            Op::Loop(start) => match self.frames.last_mut() {
+                Some(Frame::Loop { i, n, .. }) if *i + 1 < *n => {
+                    *i += 1;
+                    start
+                }
+                _ => {
+                    self.frames.pop();
+                    pc + 1
+                }
+            },
+            Op::Return => {
+                if let Some(Frame::Tail { .. }) = self.frames.last() {
+                    if self.frames.len() > base {
+                        self.frames.pop();
+                    }
+                }
+                if self.frames.len() == base {
+                    return Ok(None);
+                }
+                match self.frames.pop() {
+                    Some(Frame::Call { ret, .. }) => ret,
+                    _ => unreachable!(),
+                }
+            }
+        };
+        Ok(Some(next))
+    }
+
+    fn token(&self, pc: usize) -> &str {
+        self.sources[pc].as_ref().map_or("", |(_, token)| token)
+    }
+
+    fn underflow(&self, pc: usize) -> Error {
+        ErrorType::Underflow(self.token(pc).to_string(), 1, 0).into()
+    }
+
+    /// Locate `e` at the instruction `pc` that failed, trace it through the
+    /// frames above `base`, and drop those frames.
+    fn unwind(&mut self, mut e: Error, pc: usize, base: usize) -> Error {
+        if let Some((span, token)) = &self.sources[pc] {
+            e = e.at(span, token);
+        }
+        for frame in self.frames.drain(base..).rev() {
+            let sites = match frame {
+                Frame::Call { site, .. } => vec![(site, None)],
+                Frame::Loop { i, site, .. } => vec![(site, Some(format!("iteration {} of `do`", i)))],
+                Frame::Tail { first, last } if first != last => vec![(last, None), (first, None)],
+                Frame::Tail { first, .. } => vec![(first, None)],
+            };
+            for (site, loop_frame) in sites {
+                if let Some((span, token)) = &self.sources[site] {
+                    e = match loop_frame {
+                        Some(frame) => e.within(frame, span),
+                        None => e.at(span, token),
+                    };
+                }
+            }
+        }
+        e
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser;
+
+    /// Run `src` on an empty stack with the VM, as `eval::eval` does.
+    fn run(src: &str) -> Result<Vec<A>, Error> {
+        let (ast, words) = parser::parse(src, "").unwrap();
+        let natives = HashMap::new();
+        let mut vm = Vm::new(&words, &natives);
+        let entry = vm.compile(&ast);
+        let mut stack = vec![];
+        vm.exec(entry, &mut stack).map(|()| stack)
+    }
+
+    #[test]
+    fn tail_calls_reuse_the_frame() {
+        let stack = run(": down dup if 1 - down then ; 1000000 down").unwrap();
+        assert_eq!(stack, vec![A::from_num(0.)]);
+        // Only the first and the last of the tail calls are kept for errors.
+        let e = run(": down dup if 1 - down else + then ; 100000 down").unwrap_err();
+        assert!(matches!(e.ty, ErrorType::Underflow(..)));
+        assert!(e.trace.len() <= 3, "{:?}", e.trace);
+        let stack = run(": even dup if 1 - odd else 1 + then ; : odd dup if 1 - even then ; 100001 even");
+        assert_eq!(stack.unwrap(), vec![A::from_num(0.)]);
+    }
+
+    #[test]
+    fn loops_need_a_count() {
+        assert_eq!(run("0 3 do i + loop").unwrap(), vec![A::from_num(3.)]);
+        assert_eq!(run("5 0 do 1 + loop").unwrap(), vec![A::from_num(5.)]);
+        for src in ["-1 do loop", "1.5 do loop", "0 0 / do loop", "1 0 / do loop", "\"a\" do loop"] {
+            let e = run(src).unwrap_err();
+            assert!(matches!(e.ty, ErrorType::Msg(_)), "`{}` gave {:?}", src, e.ty);
+            assert_eq!(e.location.unwrap().token, "do");
+        }
+    }
+
+    #[test]
+    fn undefined_words_are_traced_through_calls_and_loops() {
+        let e = run(": f foo ; : g 3 do f loop ; g").unwrap_err();
+        assert!(matches!(&e.ty, ErrorType::Undefined(w) if w == "foo"));
+        let location = e.location.unwrap();
+        assert_eq!((location.token.as_str(), location.span.col), ("foo", 5));
+        let trace: Vec<_> = e.trace.iter().map(|(frame, span)| (frame.as_str(), span.col)).collect();
+        assert_eq!(trace, vec![("`f`", 20), ("iteration 0 of `do`", 17), ("`g`", 29)]);
+    }
+}